@@ -0,0 +1,188 @@
+use actix_web::{delete, post, put, web, HttpResponse, Responder, Scope};
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+    auth::AdminClaims,
+    config::{tracked_branches, update_config},
+    scheduler::{reload_config, set_scheduler_paused, IndexerCommand},
+    web::{store_failure, with_store, AppState},
+};
+
+pub fn admin_scope() -> Scope {
+    web::scope("/admin")
+        .service(reload)
+        .service(refresh_branch)
+        .service(rebuild_all)
+        .service(rebuild_branch)
+        .service(pause_scheduler)
+        .service(resume_scheduler)
+        .service(add_branch)
+        .service(remove_branch)
+        .service(purge_all_prs)
+        .service(purge_pr)
+}
+
+fn is_valid_branch_name(branch: &str) -> bool {
+    !branch.is_empty()
+        && !branch.starts_with('-')
+        && !branch.contains("..")
+        && branch
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+fn enqueue(data: &AppState, command: IndexerCommand) -> HttpResponse {
+    match data.indexer.send(command) {
+        Ok(_) => HttpResponse::Accepted().json(json!({ "success": true })),
+        Err(_) => HttpResponse::ServiceUnavailable().json(json!({
+            "success": false,
+            "detail": "Indexer is not running",
+        })),
+    }
+}
+
+fn untracked(branch: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "success": false,
+        "detail": format!("Branch {} is not tracked", branch),
+    }))
+}
+
+#[post("/reload")]
+async fn reload(claims: AdminClaims, data: web::Data<AppState>) -> impl Responder {
+    info!(sub = ?claims.sub, "Admin requested config reload");
+    match reload_config(&data.github_token, &data.indexer) {
+        Ok(config) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "branches": config.branches,
+            "refresh_interval": config.refresh_interval,
+        })),
+        Err(e) => HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "detail": e,
+        })),
+    }
+}
+
+#[post("/branches/{branch}/refresh")]
+async fn refresh_branch(
+    claims: AdminClaims,
+    data: web::Data<AppState>,
+    branch: web::Path<String>,
+) -> impl Responder {
+    let branch = branch.into_inner();
+    if !tracked_branches().contains(&branch) {
+        return untracked(&branch);
+    }
+    info!(sub = ?claims.sub, branch, "Admin requested branch refresh");
+    enqueue(&data, IndexerCommand::IndexBranches(vec![branch]))
+}
+
+#[post("/rebuild")]
+async fn rebuild_all(claims: AdminClaims, data: web::Data<AppState>) -> impl Responder {
+    info!(sub = ?claims.sub, "Admin requested full rebuild");
+    enqueue(&data, IndexerCommand::RebuildBranches(tracked_branches()))
+}
+
+#[post("/branches/{branch}/rebuild")]
+async fn rebuild_branch(
+    claims: AdminClaims,
+    data: web::Data<AppState>,
+    branch: web::Path<String>,
+) -> impl Responder {
+    let branch = branch.into_inner();
+    if !tracked_branches().contains(&branch) {
+        return untracked(&branch);
+    }
+    info!(sub = ?claims.sub, branch, "Admin requested branch rebuild");
+    enqueue(&data, IndexerCommand::RebuildBranches(vec![branch]))
+}
+
+#[post("/scheduler/pause")]
+async fn pause_scheduler(claims: AdminClaims) -> impl Responder {
+    info!(sub = ?claims.sub, "Admin paused the scheduler");
+    set_scheduler_paused(true);
+    HttpResponse::Ok().json(json!({ "success": true, "paused": true }))
+}
+
+#[post("/scheduler/resume")]
+async fn resume_scheduler(claims: AdminClaims) -> impl Responder {
+    info!(sub = ?claims.sub, "Admin resumed the scheduler");
+    set_scheduler_paused(false);
+    HttpResponse::Ok().json(json!({ "success": true, "paused": false }))
+}
+
+// Branches added or removed here only live until the next reload or restart.
+#[put("/branches/{branch}")]
+async fn add_branch(
+    claims: AdminClaims,
+    data: web::Data<AppState>,
+    branch: web::Path<String>,
+) -> impl Responder {
+    let branch = branch.into_inner();
+    if !is_valid_branch_name(&branch) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "detail": format!("Invalid branch name {}", branch),
+        }));
+    }
+    let mut added = false;
+    let config = update_config(|config| {
+        if !config.branches.contains(&branch) {
+            config.branches.push(branch.clone());
+            added = true;
+        }
+    });
+    info!(sub = ?claims.sub, branch, added, "Admin added tracked branch");
+    if added {
+        let _ = data
+            .indexer
+            .send(IndexerCommand::IndexBranches(vec![branch]));
+    }
+    HttpResponse::Ok().json(json!({ "success": true, "branches": config.branches }))
+}
+
+#[delete("/branches/{branch}")]
+async fn remove_branch(claims: AdminClaims, branch: web::Path<String>) -> impl Responder {
+    let branch = branch.into_inner();
+    let current = tracked_branches();
+    if !current.contains(&branch) {
+        return untracked(&branch);
+    }
+    if current.len() == 1 {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "detail": "At least one branch must be tracked",
+        }));
+    }
+    let config = update_config(|config| config.branches.retain(|tracked| tracked != &branch));
+    info!(sub = ?claims.sub, branch, "Admin removed tracked branch");
+    HttpResponse::Ok().json(json!({ "success": true, "branches": config.branches }))
+}
+
+#[delete("/cache/pr")]
+async fn purge_all_prs(claims: AdminClaims, data: web::Data<AppState>) -> impl Responder {
+    let purged = data.forget_lookups(None);
+    info!(sub = ?claims.sub, purged, "Admin purged PR cache");
+    HttpResponse::Ok().json(json!({ "success": true, "purged": purged }))
+}
+
+#[delete("/cache/pr/{id}")]
+async fn purge_pr(
+    claims: AdminClaims,
+    data: web::Data<AppState>,
+    pr: web::Path<u64>,
+) -> impl Responder {
+    let pr = pr.into_inner();
+    let lookups = data.forget_lookups(Some(pr));
+    let forgotten = with_store(&data, move |store| store.forget_pr_merge(pr)).await;
+    match forgotten {
+        Ok(forgotten) => {
+            let purged = lookups + forgotten as usize;
+            info!(sub = ?claims.sub, pr, purged, "Admin purged PR cache");
+            HttpResponse::Ok().json(json!({ "success": true, "purged": purged }))
+        }
+        Err(e) => store_failure(&e),
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    env, fs,
+    future::{ready, Ready},
+};
+
+use actix_web::{error::ErrorUnauthorized, web, FromRequest, HttpRequest};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+// Verifies bearer tokens for the /admin scope. HS256 tokens are checked against
+// JWT_SECRET, RS256 tokens against the PEM public key at JWT_PUBLIC_KEY. Tokens
+// must carry an `exp` claim; JWT_ISSUER and JWT_AUDIENCE, when set, must match
+// the `iss` and `aud` claims.
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminClaims {
+    pub sub: Option<String>,
+}
+
+impl JwtAuth {
+    // Returns None when no key is configured, which disables the admin API.
+    pub fn from_env() -> Result<Option<JwtAuth>, String> {
+        let algorithm = match env::var("JWT_ALGORITHM") {
+            Ok(val) => val
+                .parse::<Algorithm>()
+                .map_err(|e| format!("Invalid JWT_ALGORITHM: {}", e))?,
+            Err(_e) => match env::var("JWT_PUBLIC_KEY") {
+                Ok(_) => Algorithm::RS256,
+                Err(_) => Algorithm::HS256,
+            },
+        };
+        let key = match algorithm {
+            Algorithm::HS256 => match env::var("JWT_SECRET") {
+                Ok(secret) if !secret.is_empty() => DecodingKey::from_secret(secret.as_bytes()),
+                _ => return Ok(None),
+            },
+            Algorithm::RS256 => match env::var("JWT_PUBLIC_KEY") {
+                Ok(path) => {
+                    let pem = fs::read(&path)
+                        .map_err(|e| format!("Failed to read JWT_PUBLIC_KEY {}: {}", path, e))?;
+                    DecodingKey::from_rsa_pem(&pem)
+                        .map_err(|e| format!("Invalid JWT_PUBLIC_KEY {}: {}", path, e))?
+                }
+                Err(_e) => return Ok(None),
+            },
+            other => return Err(format!("Unsupported JWT_ALGORITHM {:?}", other)),
+        };
+
+        let mut validation = Validation::new(algorithm);
+        if let Ok(issuer) = env::var("JWT_ISSUER") {
+            validation.set_issuer(&[issuer]);
+        }
+        match env::var("JWT_AUDIENCE") {
+            Ok(audience) => validation.set_audience(&[audience]),
+            Err(_e) => validation.validate_aud = false,
+        }
+        info!(?algorithm, "Admin API enabled.");
+        Ok(Some(JwtAuth { key, validation }))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<AdminClaims, jsonwebtoken::errors::Error> {
+        decode::<AdminClaims>(token, &self.key, &self.validation).map(|data| data.claims)
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+}
+
+// Extractor guarding admin handlers: taking `AdminClaims` as an argument makes
+// the request fail with 401 unless it carries a valid `Authorization: Bearer` token.
+impl FromRequest for AdminClaims {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let auth = match req.app_data::<web::Data<Option<JwtAuth>>>() {
+            Some(auth) => auth,
+            None => return ready(Err(ErrorUnauthorized("Admin API is disabled"))),
+        };
+        let auth = match auth.as_ref() {
+            Some(auth) => auth,
+            None => return ready(Err(ErrorUnauthorized("Admin API is disabled"))),
+        };
+        let token = match bearer_token(req) {
+            Some(token) => token,
+            None => return ready(Err(ErrorUnauthorized("Missing bearer token"))),
+        };
+        ready(match auth.verify(token) {
+            Ok(claims) => Ok(claims),
+            Err(e) => {
+                warn!(error = %e, "Rejected admin token");
+                Err(ErrorUnauthorized("Invalid bearer token"))
+            }
+        })
+    }
+}
+
+// Extractor guarding the creation of watches. When WATCH_TOKEN is set, requests
+// must carry it as a bearer token; it only grants subscribing, so it can be
+// handed out without the admin credentials. Without it anyone may subscribe.
+pub struct WatchAccess;
+
+impl FromRequest for WatchAccess {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let expected = match env::var("WATCH_TOKEN") {
+            Ok(expected) if !expected.is_empty() => expected,
+            _ => return ready(Ok(WatchAccess)),
+        };
+        ready(match bearer_token(req) {
+            // Digests are compared so that the time taken says nothing about the token.
+            Some(token) if Sha256::digest(token) == Sha256::digest(expected) => Ok(WatchAccess),
+            Some(_) => Err(ErrorUnauthorized("Invalid bearer token")),
+            None => Err(ErrorUnauthorized("Missing bearer token")),
+        })
+    }
+}
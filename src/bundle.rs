@@ -0,0 +1,93 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    thread,
+    time::Duration,
+};
+
+use git2::{Error, Repository};
+use tracing::info;
+
+use crate::{
+    config::tracked_branches,
+    pull::{fetch_bundle, FetchStats},
+    scheduler::IndexerCommand,
+    shutdown::shutdown_requested,
+};
+
+// How often BUNDLE_DIR is looked at for new bundles.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Deployments without access to the remote get their updates as incremental
+// bundles dropped into this directory instead of fetching.
+pub fn bundle_dir() -> Option<PathBuf> {
+    env::var("BUNDLE_DIR").ok().map(PathBuf::from)
+}
+
+// Bundles not fetched yet, in the order they are applied. Only `.bundle`
+// files count, so a bundle can be written under another name and renamed
+// once complete.
+fn pending_bundles(dir: &Path) -> Vec<PathBuf> {
+    let mut bundles: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.is_file() && path.extension().is_some_and(|ext| ext == "bundle")
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    bundles.sort();
+    bundles
+}
+
+// Fetches every pending bundle and moves it to `applied` under the
+// directory. Stops at the first bundle that fails, as the following ones
+// usually build on it; it is tried again on the next pass.
+pub fn fetch_bundles(
+    repo: &Repository,
+    branches: &[String],
+    dir: &Path,
+) -> Result<FetchStats, Error> {
+    let mut stats = FetchStats::default();
+    let applied = dir.join("applied");
+    for bundle in pending_bundles(dir) {
+        let fetched = fetch_bundle(repo, branches, &bundle)?;
+        fs::create_dir_all(&applied)
+            .and_then(|_| fs::rename(&bundle, applied.join(bundle.file_name().unwrap())))
+            .map_err(|e| Error::from_str(&format!("Failed to move applied bundle: {}", e)))?;
+        info!(bundle = %bundle.display(), updated = ?fetched.updated, "Applied bundle.");
+        for branch in fetched.updated {
+            if !stats.updated.contains(&branch) {
+                stats.updated.push(branch);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+// Queues an indexing pass whenever new bundles show up. Bundles already
+// there on startup are applied by the first pass.
+pub fn watch_bundles(dir: PathBuf, indexer: Sender<IndexerCommand>) {
+    let mut seen = pending_bundles(&dir);
+    while !shutdown_requested() {
+        thread::sleep(POLL_INTERVAL);
+        let pending = pending_bundles(&dir);
+        if !pending.is_empty() && pending != seen {
+            info!(
+                bundles = pending.len(),
+                "New bundles found. Queueing an indexing pass."
+            );
+            if indexer
+                .send(IndexerCommand::IndexBranches(tracked_branches()))
+                .is_err()
+            {
+                break;
+            }
+        }
+        seen = pending;
+    }
+}
@@ -1,5 +1,20 @@
+use std::{env, fs, sync::RwLock};
+
+use serde::Deserialize;
+use state::InitCell;
+
+use crate::{
+    reachability::Reachability,
+    topology::{default_topology, validate_topology, Topology},
+};
+
 pub const URL: &str = "https://github.com/NixOS/nixpkgs";
 pub const REPO_PATH: &str = "nixpkgs";
+
+// Where nixpkgs is cloned from on first start, e.g. a local mirror.
+pub fn repo_url() -> String {
+    env::var("REPO_URL").unwrap_or(URL.to_string())
+}
 pub const CACHED_BRANCHES: [&str; 7] = [
     "master",
     "staging",
@@ -9,10 +24,155 @@ pub const CACHED_BRANCHES: [&str; 7] = [
     "nixos-unstable",
     "nixos-24.05",
 ];
+pub const DEFAULT_REFRESH_INTERVAL: u64 = 600;
+pub const DEFAULT_MAINTENANCE_INTERVAL: u64 = 86400;
 
 pub enum IndexState {
     Starting,
     CloningGitRepo,
+    RepairingGitRepo,
     IndexingCommit,
     Ready,
 }
+
+impl IndexState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexState::Starting => "STARTING",
+            IndexState::CloningGitRepo => "CLONING_GIT_REPO",
+            IndexState::RepairingGitRepo => "REPAIRING_GIT_REPO",
+            IndexState::IndexingCommit => "INDEXING_COMMIT",
+            IndexState::Ready => "READY",
+        }
+    }
+}
+
+// Settings that can change at runtime (SIGHUP or POST /admin/reload).
+// Values come from the environment first and are then overridden by the
+// TOML file pointed to by CONFIG_FILE, so a reload picks up file edits.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub branches: Vec<String>,
+    pub github_token: String,
+    pub refresh_interval: u64,
+    // Seconds between two git maintenance runs, 0 to never run it.
+    pub maintenance_interval: u64,
+    pub webhook_secret: String,
+    pub topology: Topology,
+    pub reachability: Reachability,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    branches: Option<Vec<String>>,
+    github_token: Option<String>,
+    refresh_interval: Option<u64>,
+    maintenance_interval: Option<u64>,
+    webhook_secret: Option<String>,
+    topology: Option<Topology>,
+    reachability: Option<Reachability>,
+}
+
+static CONFIG: InitCell<RwLock<Config>> = InitCell::new();
+
+impl Config {
+    pub fn load() -> Result<Config, String> {
+        let mut config = Config {
+            branches: match env::var("TRACKED_BRANCHES") {
+                Ok(val) => val
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|branch| !branch.is_empty())
+                    .map(String::from)
+                    .collect(),
+                Err(_e) => CACHED_BRANCHES
+                    .iter()
+                    .map(|branch| branch.to_string())
+                    .collect(),
+            },
+            github_token: env::var("GITHUB_TOKEN").unwrap_or_default(),
+            refresh_interval: match env::var("REFRESH_INTERVAL") {
+                Ok(val) => val
+                    .parse::<u64>()
+                    .map_err(|e| format!("Invalid REFRESH_INTERVAL: {}", e))?,
+                Err(_e) => DEFAULT_REFRESH_INTERVAL,
+            },
+            maintenance_interval: match env::var("MAINTENANCE_INTERVAL") {
+                Ok(val) => val
+                    .parse::<u64>()
+                    .map_err(|e| format!("Invalid MAINTENANCE_INTERVAL: {}", e))?,
+                Err(_e) => DEFAULT_MAINTENANCE_INTERVAL,
+            },
+            webhook_secret: env::var("GITHUB_WEBHOOK_SECRET").unwrap_or_default(),
+            topology: default_topology(),
+            reachability: match env::var("REACHABILITY") {
+                Ok(val) => Reachability::parse(&val)?,
+                Err(_e) => Reachability::Bitmap,
+            },
+        };
+
+        if let Ok(path) = env::var("CONFIG_FILE") {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+            let file: ConfigFile = toml::from_str(&content)
+                .map_err(|e| format!("Failed to parse config file {}: {}", path, e))?;
+            if let Some(branches) = file.branches {
+                config.branches = branches;
+            }
+            if let Some(github_token) = file.github_token {
+                config.github_token = github_token;
+            }
+            if let Some(refresh_interval) = file.refresh_interval {
+                config.refresh_interval = refresh_interval;
+            }
+            if let Some(maintenance_interval) = file.maintenance_interval {
+                config.maintenance_interval = maintenance_interval;
+            }
+            if let Some(webhook_secret) = file.webhook_secret {
+                config.webhook_secret = webhook_secret;
+            }
+            if let Some(topology) = file.topology {
+                config.topology = topology;
+            }
+            if let Some(reachability) = file.reachability {
+                config.reachability = reachability;
+            }
+        }
+
+        if config.branches.is_empty() {
+            return Err("At least one branch must be tracked".to_string());
+        }
+        if config.refresh_interval == 0 {
+            return Err("refresh_interval must be greater than zero".to_string());
+        }
+        validate_topology(&config.topology)?;
+        let mut seen = vec![];
+        config.branches.retain(|branch| {
+            let first = !seen.contains(branch);
+            seen.push(branch.clone());
+            first
+        });
+        Ok(config)
+    }
+}
+
+pub fn set_config(config: Config) {
+    if !CONFIG.set(RwLock::new(config.clone())) {
+        *CONFIG.get().write().unwrap() = config;
+    }
+}
+
+pub fn update_config<F: FnOnce(&mut Config)>(f: F) -> Config {
+    let mut config = CONFIG.get().write().unwrap();
+    f(&mut config);
+    config.clone()
+}
+
+pub fn current_config() -> Config {
+    CONFIG.get().read().unwrap().clone()
+}
+
+pub fn tracked_branches() -> Vec<String> {
+    CONFIG.get().read().unwrap().branches.clone()
+}
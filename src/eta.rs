@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    feed::rfc3339,
+    store::Store,
+    topology::{tracked_upstreams, Topology},
+};
+
+// How many branch tip changes are kept per branch.
+pub const HISTORY_MAX_ENTRIES: usize = 1000;
+// How many lag samples are kept per pair of branches. Old ones say little about
+// how fast channels move today.
+pub const LAG_MAX_SAMPLES: usize = 1000;
+// First arrivals older than this are dropped; a PR still waiting for a channel
+// after that long is not worth estimating.
+pub const ARRIVAL_RETENTION: i64 = 90 * 24 * 60 * 60;
+// Below this many samples a median is mostly noise.
+const MIN_SAMPLES: usize = 5;
+
+// A branch tip moving during an indexing pass.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Advance {
+    pub from: String,
+    pub to: String,
+    pub at: i64,
+}
+
+// When a PR is expected to reach `branch`, based on how long merges took to get
+// there from `from` in the past. The window spans the 10th to 90th percentile.
+#[derive(Serialize, Clone, Debug)]
+pub struct Eta {
+    pub branch: String,
+    pub from: String,
+    pub arrived_upstream_at: String,
+    pub estimated_at: String,
+    pub window_start: String,
+    pub window_end: String,
+    pub median_lag_seconds: i64,
+    pub samples: usize,
+    pub branch_last_advanced_at: Option<String>,
+}
+
+// Nearest rank percentile of sorted samples.
+fn percentile(sorted: &[i64], q: f64) -> i64 {
+    sorted[((sorted.len() - 1) as f64 * q).round() as usize]
+}
+
+// Estimates, for every tracked branch the PR has not reached yet, when it will.
+// Only PRs found in the local merge index have recorded arrival times.
+pub fn estimate_arrivals(
+    store: &mut dyn Store,
+    topology: &Topology,
+    branches: &[String],
+    included_in: &[bool],
+    merge_commit: &str,
+) -> Vec<Eta> {
+    let included: Vec<&String> = branches
+        .iter()
+        .zip(included_in)
+        .filter(|(_, included)| **included)
+        .map(|(branch, _)| branch)
+        .collect();
+    let mut estimates = vec![];
+    for branch in branches.iter().filter(|branch| !included.contains(branch)) {
+        // Measure from the upstream the PR reached last, that is the closest one.
+        let upstream = tracked_upstreams(topology, branch, branches)
+            .into_iter()
+            .filter(|upstream| included.contains(&upstream))
+            .filter_map(|upstream| {
+                let arrived = store.arrival(&upstream, merge_commit).ok()??;
+                Some((upstream, arrived))
+            })
+            .max_by_key(|(_, arrived)| *arrived);
+        let Some((upstream, arrived)) = upstream else {
+            continue;
+        };
+        let mut samples = store.lag_samples(&upstream, branch).unwrap_or_default();
+        if samples.len() < MIN_SAMPLES {
+            continue;
+        }
+        samples.sort_unstable();
+        let median = percentile(&samples, 0.5);
+        let last_advance = store.history(branch, 1).unwrap_or_default();
+        estimates.push(Eta {
+            branch: branch.clone(),
+            from: upstream,
+            arrived_upstream_at: rfc3339(arrived),
+            estimated_at: rfc3339(arrived + median),
+            window_start: rfc3339(arrived + percentile(&samples, 0.1)),
+            window_end: rfc3339(arrived + percentile(&samples, 0.9)),
+            median_lag_seconds: median,
+            samples: samples.len(),
+            branch_last_advanced_at: last_advance.first().map(|advance| rfc3339(advance.at)),
+        });
+    }
+    estimates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{store::StoreConfig, topology::default_topology};
+
+    const MERGE: &str = "1111111111111111111111111111111111111111";
+
+    #[test]
+    fn percentile_bounds() {
+        let samples: Vec<i64> = (1..=10).collect();
+        assert_eq!(percentile(&samples, 0.0), 1);
+        assert_eq!(percentile(&samples, 0.1), 2);
+        assert_eq!(percentile(&samples, 0.5), 6);
+        assert_eq!(percentile(&samples, 0.9), 9);
+        assert_eq!(percentile(&samples, 1.0), 10);
+        assert_eq!(percentile(&[42], 0.0), 42);
+        assert_eq!(percentile(&[42], 1.0), 42);
+    }
+
+    // A PR that reached master at 1000, with the given master -> nixos-unstable lags.
+    fn estimate(lags: &[i64]) -> Vec<Eta> {
+        let mut store = StoreConfig::memory().open().unwrap();
+        store
+            .save_arrivals("master", &[(MERGE.to_string(), 1_000)], 0)
+            .unwrap();
+        store
+            .push_lag_samples("master", "nixos-unstable", lags)
+            .unwrap();
+        let branches = vec!["master".to_string(), "nixos-unstable".to_string()];
+        estimate_arrivals(
+            store.as_mut(),
+            &default_topology(),
+            &branches,
+            &[true, false],
+            MERGE,
+        )
+    }
+
+    #[test]
+    fn too_few_samples_give_no_estimate() {
+        assert!(estimate(&[10, 20, 30, 40]).is_empty());
+    }
+
+    #[test]
+    fn estimate_spans_the_sampled_lags() {
+        let estimates = estimate(&[50, 10, 40, 20, 30]);
+        assert_eq!(estimates.len(), 1);
+        let eta = &estimates[0];
+        assert_eq!(eta.branch, "nixos-unstable");
+        assert_eq!(eta.from, "master");
+        assert_eq!(eta.samples, 5);
+        assert_eq!(eta.median_lag_seconds, 30);
+        assert_eq!(eta.arrived_upstream_at, rfc3339(1_000));
+        assert_eq!(eta.estimated_at, rfc3339(1_030));
+        assert_eq!(eta.window_start, rfc3339(1_010));
+        assert_eq!(eta.window_end, rfc3339(1_050));
+        assert_eq!(eta.branch_last_advanced_at, None);
+    }
+}
@@ -0,0 +1,162 @@
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::tracked_branches,
+    web::{store_failure, with_store, AppState},
+};
+
+// How many landed PRs are kept per branch.
+pub const FEED_MAX_ENTRIES: usize = 500;
+
+// A PR whose merge commit became reachable from a branch during an indexing pass.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FeedEntry {
+    pub pr: u64,
+    pub commit: String,
+    pub title: String,
+    pub merged_at: i64,
+    pub landed_at: i64,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub(crate) fn rfc3339(timestamp: i64) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .to_rfc3339()
+}
+
+pub fn render_feed(branch: &str, self_url: &str, entries: &[FeedEntry]) -> String {
+    let updated = entries.first().map(|entry| entry.landed_at).unwrap_or(0);
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         <id>urn:fast-nixpkgs-tracker:branch:{branch}</id>\n\
+         <title>PRs landing in {branch}</title>\n\
+         <link rel=\"self\" href=\"{self_url}\"/>\n\
+         <link rel=\"alternate\" href=\"https://github.com/NixOS/nixpkgs/commits/{branch}\"/>\n\
+         <updated>{updated}</updated>\n\
+         <author><name>fast-nixpkgs-tracker</name></author>\n",
+        branch = escape(branch),
+        self_url = escape(self_url),
+        updated = rfc3339(updated),
+    );
+    for entry in entries {
+        xml.push_str(&format!(
+            "<entry>\n\
+             <id>urn:fast-nixpkgs-tracker:branch:{branch}:{commit}</id>\n\
+             <title>#{pr}: {title}</title>\n\
+             <link href=\"https://github.com/NixOS/nixpkgs/pull/{pr}\"/>\n\
+             <published>{published}</published>\n\
+             <updated>{updated}</updated>\n\
+             <summary>PR #{pr} reached {branch} with merge commit {commit}.</summary>\n\
+             </entry>\n",
+            branch = escape(branch),
+            commit = entry.commit,
+            pr = entry.pr,
+            title = escape(&entry.title),
+            published = rfc3339(entry.merged_at),
+            updated = rfc3339(entry.landed_at),
+        ));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+#[get("/branch/{name}/feed.atom")]
+async fn branch_feed(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    name: web::Path<String>,
+) -> impl Responder {
+    let branch = name.into_inner();
+    if !tracked_branches().contains(&branch) {
+        return HttpResponse::NotFound().body(format!("Branch {} is not tracked", branch));
+    }
+    let lookup = branch.clone();
+    let entries = match with_store(&data, move |store| store.feed(&lookup)).await {
+        Ok(entries) => entries,
+        Err(e) => return store_failure(&e),
+    };
+    let info = req.connection_info();
+    let self_url = format!("{}://{}{}", info.scheme(), info.host(), req.path());
+    HttpResponse::Ok()
+        .content_type("application/atom+xml; charset=utf-8")
+        .body(render_feed(&branch, &self_url, &entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_lists_entries_newest_first() {
+        let entries = [
+            FeedEntry {
+                pr: 2,
+                commit: "b".repeat(40),
+                title: "world: init at 2.0".to_string(),
+                merged_at: 1_700_000_600,
+                landed_at: 1_700_003_600,
+            },
+            FeedEntry {
+                pr: 1,
+                commit: "a".repeat(40),
+                title: "hello: <init> & \"fix\"".to_string(),
+                merged_at: 1_700_000_000,
+                landed_at: 1_700_000_060,
+            },
+        ];
+        let xml = render_feed(
+            "nixos-unstable",
+            "http://127.0.0.1:8080/branch/nixos-unstable/feed.atom",
+            &entries,
+        );
+        let expected = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+             <id>urn:fast-nixpkgs-tracker:branch:nixos-unstable</id>\n\
+             <title>PRs landing in nixos-unstable</title>\n\
+             <link rel=\"self\" href=\"http://127.0.0.1:8080/branch/nixos-unstable/feed.atom\"/>\n\
+             <link rel=\"alternate\" href=\"https://github.com/NixOS/nixpkgs/commits/nixos-unstable\"/>\n\
+             <updated>2023-11-14T23:13:20+00:00</updated>\n\
+             <author><name>fast-nixpkgs-tracker</name></author>\n\
+             <entry>\n\
+             <id>urn:fast-nixpkgs-tracker:branch:nixos-unstable:{b}</id>\n\
+             <title>#2: world: init at 2.0</title>\n\
+             <link href=\"https://github.com/NixOS/nixpkgs/pull/2\"/>\n\
+             <published>2023-11-14T22:23:20+00:00</published>\n\
+             <updated>2023-11-14T23:13:20+00:00</updated>\n\
+             <summary>PR #2 reached nixos-unstable with merge commit {b}.</summary>\n\
+             </entry>\n\
+             <entry>\n\
+             <id>urn:fast-nixpkgs-tracker:branch:nixos-unstable:{a}</id>\n\
+             <title>#1: hello: &lt;init&gt; &amp; &quot;fix&quot;</title>\n\
+             <link href=\"https://github.com/NixOS/nixpkgs/pull/1\"/>\n\
+             <published>2023-11-14T22:13:20+00:00</published>\n\
+             <updated>2023-11-14T22:14:20+00:00</updated>\n\
+             <summary>PR #1 reached nixos-unstable with merge commit {a}.</summary>\n\
+             </entry>\n\
+             </feed>\n",
+            a = "a".repeat(40),
+            b = "b".repeat(40),
+        );
+        assert_eq!(xml, expected);
+    }
+
+    #[test]
+    fn empty_feed_is_valid() {
+        let xml = render_feed("master", "http://localhost/branch/master/feed.atom", &[]);
+        assert!(xml.contains("<updated>1970-01-01T00:00:00+00:00</updated>\n"));
+        assert!(!xml.contains("<entry>"));
+        assert!(xml.ends_with("</author>\n</feed>\n"));
+    }
+}
@@ -0,0 +1,139 @@
+use std::{env, error::Error};
+
+use octocrab::{models::IssueState, Octocrab};
+
+#[derive(Clone)]
+pub enum PrState {
+    Open,
+    // `merged` also covers squashed and rebased PRs. Their head commits never
+    // land, `merge_commit` is what did: the merge, squash or last rebased commit.
+    Closed {
+        commits: Vec<String>,
+        merged: bool,
+        merge_commit: Option<String>,
+    },
+    // Found in the local merge index, no GitHub call needed.
+    Merged {
+        merge_commit: String,
+        commits: Vec<String>,
+    },
+}
+
+// Overridable so the tracker can be pointed at GitHub Enterprise or a mock server.
+fn api_url() -> String {
+    env::var("GITHUB_API_URL").unwrap_or("https://api.github.com".to_string())
+}
+
+fn octocrab(github_token: &str) -> Octocrab {
+    let builder = Octocrab::builder().base_uri(api_url()).unwrap();
+    match !github_token.is_empty() {
+        true => builder
+            .personal_token(github_token.to_string())
+            .build()
+            .unwrap(),
+        false => builder.build().unwrap(),
+    }
+}
+
+// Looks the PR up on GitHub. Errors are already phrased for API consumers.
+pub async fn fetch_pr(github_token: &str, pr_number: u64) -> Result<PrState, String> {
+    let pr = octocrab(github_token)
+        .pulls("NixOS", "nixpkgs")
+        .get(pr_number)
+        .await;
+
+    match pr {
+        Ok(pr_value) => match pr_value.state {
+            Some(IssueState::Open) => Ok(PrState::Open),
+            Some(IssueState::Closed) => Ok(PrState::Closed {
+                commits: fetch_pr_commits(github_token, pr_number).await?,
+                merged: pr_value.merged_at.is_some(),
+                // Unmerged PRs have one too, for GitHub's test merge.
+                merge_commit: match pr_value.merged_at {
+                    Some(_) => pr_value.merge_commit_sha,
+                    None => None,
+                },
+            }),
+            Some(_) => Err("Unexpected pr state, should be OPEN or CLOSED".to_string()),
+            None => Err("Unexpected pr state, should not be NONE".to_string()),
+        },
+        Err(result) => {
+            let source = match result.source() {
+                Some(source) => source.to_string(),
+                None => result.to_string(),
+            };
+            match source.contains("rate limit") {
+                true => Err("API rate limit".to_string()),
+                false => Err(source),
+            }
+        }
+    }
+}
+
+// GitHub lists at most this many commits of a PR, however many pages are asked for.
+pub const MAX_LISTED_COMMITS: usize = 250;
+
+// The `rel="next"` target of a `Link` header, present until the last page.
+fn next_page(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| param.trim() == "rel=\"next\"")
+            .then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+pub async fn fetch_pr_commits(github_token: &str, pr_number: u64) -> Result<Vec<String>, String> {
+    let client = reqwest::Client::new();
+    let mut commits = vec![];
+    let mut url = Some(format!(
+        "{}/repos/NixOS/nixpkgs/pulls/{}/commits?per_page=100&page=1",
+        api_url(),
+        pr_number
+    ));
+    while let Some(page) = url {
+        let mut request = client
+            .get(page)
+            .header("Accept", "application/json")
+            .header("User-Agent", "Rust");
+        if !github_token.is_empty() {
+            request = request.header("Authorization", format!("Bearer {}", github_token));
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|_e| "Failed to fetch data from GitHub".to_string())?;
+        url = response
+            .headers()
+            .get("Link")
+            .and_then(|link| link.to_str().ok())
+            .and_then(next_page);
+        let json = response
+            .json::<serde_json::Value>()
+            .await
+            .map_err(|_e| "Failed to fetch data from GitHub".to_string())?;
+        match json.as_array() {
+            Some(page) => commits.extend(
+                page.iter()
+                    .filter_map(|commit| commit["sha"].as_str())
+                    .map(String::from),
+            ),
+            None => return Err("Failed to fetch data from GitHub".to_string()),
+        }
+    }
+    // Anything past the limit is silently left out, and a PR missing commits
+    // would look included too early.
+    if commits.len() >= MAX_LISTED_COMMITS {
+        return Err(format!(
+            "PR has {} or more commits, more than GitHub lists",
+            MAX_LISTED_COMMITS
+        ));
+    }
+    Ok(commits)
+}
@@ -0,0 +1,591 @@
+use std::{
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use git2::{Error, Oid, Repository};
+use tracing::{debug, error, info, info_span, instrument, warn};
+
+use crate::{
+    config::{current_config, IndexState},
+    eta::{Advance, ARRIVAL_RETENTION},
+    feed::FeedEntry,
+    fetch_branches,
+    merges::{first_containing, landing_commit, merged_pr, merged_prs_between},
+    pull::check_repo,
+    reachability::Reachability,
+    shutdown::shutdown_requested,
+    store::{Store, StoreError},
+    switch_branch,
+    topology::tracked_upstreams,
+    update_git_repo,
+};
+
+const COMMIT_BATCH_SIZE: usize = 1000;
+
+fn store_error(context: &str) -> impl Fn(StoreError) -> Error + '_ {
+    move |e| Error::from_str(&format!("{}: {}", context, e))
+}
+
+// Fails only when the git repo is broken and has to be cloned again.
+pub fn index_branches(
+    repo: &Repository,
+    store: &mut dyn Store,
+    branches: &[String],
+) -> Result<(), String> {
+    if branches.is_empty() {
+        return Ok(());
+    }
+    let _span = info_span!("index").entered();
+    let start = Instant::now();
+    info!(?branches, "Indexing commits. It may take a while...");
+    let _ = store.prepare(branches);
+    let _ = remove_partial_deltas(store, branches);
+    cache_branches(repo, store, branches)?;
+    if shutdown_requested() {
+        warn!(elapsed = ?start.elapsed(), "Indexing cancelled by shutdown.");
+        return Ok(());
+    }
+    let _ = store.set_index_state(IndexState::Ready);
+    info!(elapsed = ?start.elapsed(), "Successfully indexing commits.");
+    Ok(())
+}
+
+// A previous run may have been killed while filling a delta. Those are never
+// read, so just drop them before starting over.
+fn remove_partial_deltas(store: &mut dyn Store, branches: &[String]) -> Result<(), StoreError> {
+    for branch in branches {
+        store.discard_branch_commits(branch, true)?;
+    }
+    Ok(())
+}
+
+// Records when the given merges reached the branch, and how long each took
+// coming from every tracked upstream it was seen in before. Merges the branch
+// already had, which come back after it was reset, keep their first arrival
+// and are sampled only once.
+fn record_arrivals(
+    store: &mut dyn Store,
+    branch: &str,
+    entries: &[FeedEntry],
+    upstreams: &[String],
+) -> Result<(), StoreError> {
+    let mut arrived = vec![];
+    for entry in entries {
+        if store.arrival(branch, &entry.commit)?.is_none() {
+            arrived.push(entry);
+        }
+    }
+    if arrived.is_empty() {
+        return Ok(());
+    }
+    let arrivals: Vec<(String, i64)> = arrived
+        .iter()
+        .map(|entry| (entry.commit.clone(), entry.landed_at))
+        .collect();
+    store.save_arrivals(branch, &arrivals, arrived[0].landed_at - ARRIVAL_RETENTION)?;
+
+    for upstream in upstreams {
+        let mut lags = vec![];
+        for entry in &arrived {
+            if let Some(arrived) = store.arrival(upstream, &entry.commit)? {
+                if arrived <= entry.landed_at {
+                    lags.push(entry.landed_at - arrived);
+                }
+            }
+        }
+        store.push_lag_samples(upstream, branch, &lags)?;
+    }
+    Ok(())
+}
+
+// Buffers PR merges found while walking a branch and writes them in batches.
+struct MergeScanner {
+    merges: Vec<(u64, String)>,
+}
+
+impl MergeScanner {
+    fn scan(
+        &mut self,
+        repo: &Repository,
+        store: &mut dyn Store,
+        commit_id: Oid,
+    ) -> Result<(), Error> {
+        if let Some(merge) = merged_pr(&repo.find_commit(commit_id)?) {
+            self.merges.push((merge.pr, merge.commit));
+        }
+        if self.merges.len() >= COMMIT_BATCH_SIZE {
+            self.flush(store)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, store: &mut dyn Store) -> Result<(), Error> {
+        store
+            .save_pr_merges(&self.merges)
+            .map_err(store_error("Failed to write PR merges"))?;
+        self.merges.clear();
+        Ok(())
+    }
+}
+
+// Records the PRs merged between the previously indexed tip and HEAD in the PR
+// index, the branch feed and the arrival history. Nothing is recorded on the
+// first index of a branch, when all of history would be "new"; the full walk
+// takes care of the PR index then.
+fn record_landed_prs(
+    branch: &str,
+    repo: &Repository,
+    store: &mut dyn Store,
+    previous_tip: &str,
+) -> Result<usize, Error> {
+    if previous_tip.is_empty() {
+        return Ok(0);
+    }
+    let old_tip = Oid::from_str(previous_tip)?;
+    let new_tip = repo.head()?.peel_to_commit()?.id();
+    if old_tip == new_tip {
+        return Ok(0);
+    }
+    let landed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let entries: Vec<FeedEntry> = merged_prs_between(repo, old_tip, new_tip)?
+        .into_iter()
+        .map(|merge| FeedEntry {
+            pr: merge.pr,
+            commit: merge.commit,
+            title: merge.title,
+            merged_at: merge.time,
+            landed_at,
+        })
+        .collect();
+    let merges: Vec<(u64, String)> = entries
+        .iter()
+        .map(|entry| (entry.pr, entry.commit.clone()))
+        .collect();
+    store
+        .save_pr_merges(&merges)
+        .map_err(store_error("Failed to write PR merges"))?;
+    store
+        .push_feed_entries(branch, &entries)
+        .map_err(store_error("Failed to write feed"))?;
+    let advance = Advance {
+        from: previous_tip.to_string(),
+        to: new_tip.to_string(),
+        at: landed_at,
+    };
+    let config = current_config();
+    let upstreams = tracked_upstreams(&config.topology, branch, &config.branches);
+    store
+        .push_history(branch, &advance)
+        .and_then(|_| record_arrivals(store, branch, &entries, &upstreams))
+        .map_err(store_error("Failed to write branch history"))?;
+    Ok(entries.len())
+}
+
+// Keeps the branch's first-parent history by position: the merges and channel
+// bumps that moved the branch. Commits are added oldest first, so an
+// interrupted run resumes where it stopped.
+fn index_first_parents(
+    branch: &str,
+    repo: &Repository,
+    store: &mut dyn Store,
+) -> Result<usize, Error> {
+    let write_error = store_error("Failed to write first parents");
+    let top = store.first_parent_top(branch).map_err(&write_error)?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.simplify_first_parent()?;
+    revwalk.push_head()?;
+    let mut added = vec![];
+    let mut next = None;
+    for commit_id in revwalk {
+        if shutdown_requested() {
+            return Err(Error::from_str("Indexing cancelled by shutdown"));
+        }
+        let commit_id = commit_id?.to_string();
+        if let Some((tip, position)) = &top {
+            if commit_id == *tip {
+                next = Some(position + 1);
+                break;
+            }
+        }
+        added.push(commit_id);
+    }
+    let next = match next {
+        Some(next) => next,
+        None if top.is_none() => 0,
+        None => {
+            warn!("First-parent history was rewritten. Indexing it again.");
+            store.clear_first_parents(branch).map_err(&write_error)?;
+            // Cached reachability assumed the branch only moves forward.
+            store.clear_reachable(branch).map_err(&write_error)?;
+            0
+        }
+    };
+    added.reverse();
+    for (chunk, commits) in added.chunks(COMMIT_BATCH_SIZE).enumerate() {
+        if shutdown_requested() {
+            return Err(Error::from_str("Indexing cancelled by shutdown"));
+        }
+        store
+            .add_first_parents(branch, next + chunk * COMMIT_BATCH_SIZE, commits)
+            .map_err(&write_error)?;
+    }
+    Ok(added.len())
+}
+
+// The first-parent commit of `branch` that made `commit` reachable from it.
+// The store is only locked for single lookups, as the reachability checks in
+// between can take a while.
+pub fn branch_landing_commit(
+    repo: &Repository,
+    store: &Mutex<Box<dyn Store>>,
+    branch: &str,
+    commit: Oid,
+) -> Result<Option<Oid>, Error> {
+    let read_error = store_error("Failed to read first parents");
+    let len = store
+        .lock()
+        .unwrap()
+        .first_parent_count(branch)
+        .map_err(&read_error)?;
+    if len == 0 {
+        let tip = store.lock().unwrap().branch_tip(branch).unwrap_or_default();
+        return match tip {
+            Some(tip) => landing_commit(repo, Oid::from_str(&tip)?, commit),
+            None => Ok(None),
+        };
+    }
+    first_containing(repo, len, commit, |position| {
+        let commit_id = store
+            .lock()
+            .unwrap()
+            .first_parent_at(branch, position)
+            .map_err(&read_error)?
+            .ok_or_else(|| Error::from_str("First-parent index changed during lookup"))?;
+        Oid::from_str(&commit_id)
+    })
+}
+
+// Forget what we know about these branches so the next pass indexes them from scratch.
+pub fn reset_branches(store: &mut dyn Store, branches: &[String]) -> Result<(), StoreError> {
+    for branch in branches {
+        store.reset_branch(branch)?;
+    }
+    Ok(())
+}
+
+#[instrument(skip(repo, store))]
+pub fn write_branch_cache(
+    branch: &str,
+    repo: &Repository,
+    store: &mut dyn Store,
+    is_delta_update: bool,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let previous_tip = store
+        .branch_tip(branch)
+        .unwrap_or_default()
+        .unwrap_or_default();
+    // Parse every commit message only until the branch's history has been scanned once.
+    let scan_merges = previous_tip.is_empty() || !store.merges_scanned(branch).unwrap_or(false);
+    let mut scanner = MergeScanner { merges: vec![] };
+    let mut revwalk = repo.revwalk()?;
+    let mut latest_sha1: String = "".to_string();
+    let mut commit_count: usize = 0;
+    revwalk.set_sorting(git2::Sort::REVERSE)?;
+    revwalk.push_head()?;
+
+    let write_error = store_error("Failed to write branch commits");
+    let mut batch: Vec<Oid> = Vec::with_capacity(COMMIT_BATCH_SIZE);
+    let build_bitmap = current_config().reachability == Reachability::Bitmap;
+    let delta = is_delta_update && build_bitmap;
+    if !delta {
+        store
+            .discard_branch_commits(branch, false)
+            .map_err(&write_error)?;
+    }
+    // With graph reachability the membership is dropped above, and history is
+    // only walked for the PR merge index.
+    if build_bitmap || scan_merges {
+        for commit_id in revwalk {
+            if shutdown_requested() {
+                match delta {
+                    // The live membership is untouched until the delta is finished, so only the delta is lost.
+                    true => {
+                        let _ = store.discard_branch_commits(branch, true);
+                    }
+                    // The membership is incomplete; forget the tip so the next run does a full index.
+                    false => {
+                        let _ = store.reset_branch(branch);
+                    }
+                }
+                return Err(Error::from_str("Indexing cancelled by shutdown"));
+            }
+            let commit_id = commit_id?;
+            if scan_merges {
+                scanner.scan(repo, store, commit_id)?;
+            }
+            if build_bitmap {
+                batch.push(commit_id);
+            }
+            if batch.len() == COMMIT_BATCH_SIZE {
+                store
+                    .add_branch_commits(branch, delta, &batch)
+                    .map_err(&write_error)?;
+                batch.clear();
+            }
+            latest_sha1 = commit_id.to_string();
+            commit_count += 1;
+        }
+        if build_bitmap {
+            store
+                .add_branch_commits(branch, delta, &batch)
+                .and_then(|_| store.finish_branch_commits(branch, delta))
+                .map_err(&write_error)?;
+        }
+    } else {
+        latest_sha1 = repo.head()?.peel_to_commit()?.id().to_string();
+    }
+
+    if scan_merges {
+        scanner.flush(store)?;
+        store
+            .set_merges_scanned(branch)
+            .map_err(store_error("Failed to write PR merges"))?;
+    }
+
+    info!(
+        commits = commit_count,
+        tip = %latest_sha1,
+        elapsed = ?start.elapsed(),
+        "Wrote branch cache."
+    );
+    match record_landed_prs(branch, repo, store, &previous_tip) {
+        Ok(landed) if landed > 0 => info!(landed, "Recorded newly landed PRs."),
+        Ok(_) => {}
+        Err(e) => warn!(error = %e, "Failed to record newly landed PRs"),
+    }
+    match index_first_parents(branch, repo, store) {
+        Ok(added) => debug!(added, "Indexed first parents."),
+        Err(e) => warn!(error = %e, "Failed to index first parents"),
+    }
+    store
+        .set_branch_tip(branch, &latest_sha1)
+        .map_err(store_error("Failed to write branch tip"))?;
+    if latest_sha1 != previous_tip {
+        store
+            .forget_unreachable(branch)
+            .map_err(store_error("Failed to write reachability"))?;
+    }
+
+    Ok(())
+}
+
+pub fn cache_branches(
+    repo: &Repository,
+    store: &mut dyn Store,
+    branches: &[String],
+) -> Result<(), String> {
+    // A failed fetch still indexes what was fetched before, unless the
+    // failure comes from a broken repo.
+    let pruned = match fetch_branches(repo, branches) {
+        Ok(stats) => stats.pruned,
+        Err(_e) if shutdown_requested() => return Ok(()),
+        Err(_e) => {
+            if let Err(problem) = check_repo(repo) {
+                error!(problem, "Git repo is broken. Stopping indexing.");
+                return Err(problem);
+            }
+            vec![]
+        }
+    };
+    for branch in branches {
+        if shutdown_requested() {
+            break;
+        }
+        if pruned.contains(branch) {
+            warn!(
+                branch,
+                "Branch was deleted on the remote. Keeping its index as it is."
+            );
+            continue;
+        }
+        let _span = info_span!("index_branch", branch).entered();
+        let start = Instant::now();
+        let remote_branch_name = match branch.as_str() {
+            "master" => "master",
+            _ => &format!("origin/{}", branch),
+        };
+        // A bare clone has nothing to check out. HEAD points at the local
+        // branch, which `update_git_repo` moves to the fetched tip.
+        let switched = match repo.is_bare() {
+            true => repo.set_head(&format!("refs/heads/{}", branch)),
+            false => switch_branch(remote_branch_name, repo),
+        };
+        match switched {
+            Ok(_) => {
+                info!("Indexing {} branch. Please wait.", branch);
+                let previous_tip = store.branch_tip(branch).unwrap_or_default();
+
+                if previous_tip.is_some() {
+                    let _ = store.set_index_state(IndexState::Ready); // Assume previous cache for all branches is available
+                    info!("Branch {} is already indexed. Do A/B updates.", branch);
+                    let _ = update_git_repo(repo, branch);
+                    let _ = write_branch_cache(branch, repo, store, true);
+                } else {
+                    // A branch added at runtime must not take the whole server out of service.
+                    let state = store.index_state().unwrap_or_default();
+                    if state != "READY" {
+                        let _ = store.set_index_state(IndexState::IndexingCommit);
+                    }
+                    info!("Branch {} is not indexed. Do full updates.", branch);
+                    let _ = update_git_repo(repo, branch);
+                    let _ = write_branch_cache(branch, repo, store, true);
+                }
+                info!(elapsed = ?start.elapsed(), "Finished indexing branch.");
+            }
+            Err(e) => error!(error = %e, "Failed to checkout branch {}", branch),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{merges::tests::Fixture, store::StoreConfig};
+
+    fn entry(pr: u64, landed_at: i64) -> FeedEntry {
+        FeedEntry {
+            pr,
+            commit: format!("{:040}", pr),
+            title: format!("PR {}", pr),
+            merged_at: 0,
+            landed_at,
+        }
+    }
+
+    #[test]
+    fn arrivals_are_sampled_once() {
+        let mut store = StoreConfig::memory().open().unwrap();
+        let upstreams = vec!["master".to_string()];
+        let first = [entry(1, 1_000)];
+        record_arrivals(store.as_mut(), "master", &first, &[]).unwrap();
+        record_arrivals(
+            store.as_mut(),
+            "nixos-unstable",
+            &[entry(1, 1_600)],
+            &upstreams,
+        )
+        .unwrap();
+        // nixos-unstable was reset and advanced again over the same merge.
+        record_arrivals(
+            store.as_mut(),
+            "nixos-unstable",
+            &[entry(1, 9_000), entry(2, 9_000)],
+            &upstreams,
+        )
+        .unwrap();
+
+        assert_eq!(
+            store
+                .arrival("nixos-unstable", &entry(1, 0).commit)
+                .unwrap(),
+            Some(1_600)
+        );
+        assert_eq!(
+            store
+                .arrival("nixos-unstable", &entry(2, 0).commit)
+                .unwrap(),
+            Some(9_000)
+        );
+        assert_eq!(
+            store.lag_samples("master", "nixos-unstable").unwrap(),
+            vec![600]
+        );
+    }
+
+    fn first_parents(store: &mut dyn Store, branch: &str) -> Vec<Oid> {
+        let len = store.first_parent_count(branch).unwrap();
+        (0..len)
+            .map(|position| {
+                let commit = store.first_parent_at(branch, position).unwrap().unwrap();
+                Oid::from_str(&commit).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_parents_are_indexed_where_the_last_pass_stopped() {
+        let mut fixture = Fixture::new();
+        let mut store = StoreConfig::memory().open().unwrap();
+        let c0 = fixture.commit(&[], "Initial commit");
+        let f1 = fixture.commit(&[c0], "hello: init at 1.0");
+        let m1 = fixture.commit(&[c0, f1], "Merge pull request #1 from alice/hello");
+        fixture.checkout("master", m1);
+        assert_eq!(
+            index_first_parents("master", &fixture.repo, store.as_mut()).unwrap(),
+            2
+        );
+
+        let c2 = fixture.commit(&[m1], "hello: 1.0 -> 1.1");
+        let c3 = fixture.commit(&[c2], "hello: 1.1 -> 1.2");
+        fixture.checkout("master", c3);
+        assert_eq!(
+            index_first_parents("master", &fixture.repo, store.as_mut()).unwrap(),
+            2
+        );
+        assert_eq!(first_parents(store.as_mut(), "master"), [c0, m1, c2, c3]);
+        assert_eq!(
+            index_first_parents("master", &fixture.repo, store.as_mut()).unwrap(),
+            0
+        );
+
+        let store = Mutex::new(store);
+        assert_eq!(
+            branch_landing_commit(&fixture.repo, &store, "master", f1).unwrap(),
+            Some(m1)
+        );
+        assert_eq!(
+            branch_landing_commit(&fixture.repo, &store, "master", c2).unwrap(),
+            Some(c2)
+        );
+    }
+
+    #[test]
+    fn rewritten_first_parents_are_indexed_again() {
+        let mut fixture = Fixture::new();
+        let mut store = StoreConfig::memory().open().unwrap();
+        let c0 = fixture.commit(&[], "Initial commit");
+        let s1 = fixture.commit(&[c0], "stdenv: rebuild the world");
+        let s2 = fixture.commit(&[s1], "stdenv: fix the world");
+        fixture.checkout("staging", s2);
+        index_first_parents("staging", &fixture.repo, store.as_mut()).unwrap();
+        store
+            .save_reachable("staging", &s1.to_string(), "1")
+            .unwrap();
+
+        // staging is force-pushed over s1 and s2.
+        let r1 = fixture.commit(&[c0], "stdenv: rebuild the world again");
+        fixture.checkout("staging", r1);
+        assert_eq!(
+            index_first_parents("staging", &fixture.repo, store.as_mut()).unwrap(),
+            2
+        );
+        assert_eq!(first_parents(store.as_mut(), "staging"), [c0, r1]);
+        assert_eq!(store.reachable("staging", &s1.to_string()).unwrap(), None);
+
+        let store = Mutex::new(store);
+        assert_eq!(
+            branch_landing_commit(&fixture.repo, &store, "staging", s1).unwrap(),
+            None
+        );
+        assert_eq!(
+            branch_landing_commit(&fixture.repo, &store, "staging", c0).unwrap(),
+            Some(c0)
+        );
+    }
+}
@@ -0,0 +1,21 @@
+use std::env;
+
+use tracing_subscriber::{fmt, EnvFilter};
+
+// Log verbosity follows RUST_LOG (defaults to "info"). Set LOG_FORMAT=json to
+// emit one JSON object per line, which plays nicer with journald and log collectors.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let json = matches!(env::var("LOG_FORMAT"), Ok(val) if val.eq_ignore_ascii_case("json"));
+
+    let builder = fmt().with_env_filter(filter).with_target(true);
+    if json {
+        builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init();
+    } else {
+        builder.init();
+    }
+}
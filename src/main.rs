@@ -1,90 +1,340 @@
+mod admin;
+mod auth;
+mod bundle;
 mod config;
+mod eta;
+mod feed;
+mod github;
+mod indexer;
+mod logging;
+mod maintenance;
+mod memory_store;
+mod merges;
 mod pull;
+mod reachability;
 mod redis_database;
+mod scheduler;
+mod shutdown;
+mod sqlite_store;
+mod store;
+mod topology;
+mod watch;
 mod web;
+mod webhook;
 use actix_web::Result;
-use config::{IndexState, REPO_PATH, URL};
-use ctrlc;
+use bundle::{bundle_dir, fetch_bundles, watch_bundles};
+use config::{repo_url, set_config, Config, IndexState, REPO_PATH};
 use git2::{build::CheckoutBuilder, Error, Repository};
-use pull::{do_fetch, do_merge};
-use redis_database::{index_redis, open_redis_connection, set_index_state};
+use logging::init_logging;
+use pull::{
+    bundle_path, check_repo, clone_repo, convert_repo, do_fetch, do_merge, fetch_bundle,
+    fetch_filtered, follow_branch, move_aside, point_origin, remote_tracking_ref, CloneMode,
+    FetchStats,
+};
+use redis_database::RedisConfig;
+use scheduler::{reload_config, run_scheduler, subscribe_index_events, IndexerCommand};
+use shutdown::{request_shutdown, shutdown_requested};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::env;
+use std::path::Path;
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use store::{Store, StoreConfig};
+use tracing::{error, info, info_span, warn};
+use watch::run_notifier;
 use web::server;
 
 fn main() -> Result<(), Error> {
-    let (tx, rx) = channel();
-    let redis_url = match env::var("REDIS_URL") {
-        Ok(val) => val,
-        Err(_e) => panic!("Provide REDIS_URL to continue."),
+    init_logging();
+    let (indexer_tx, indexer_rx) = channel();
+    let store_config = match StoreConfig::from_env() {
+        Ok(store_config) => store_config,
+        Err(e) => panic!("Invalid storage configuration: {}", e),
     };
-    let github_token = match env::var("GITHUB_TOKEN") {
-        Ok(val) => val,
-        Err(_e) => {
-            println!(
-                "Warning: You do not provide GITHUB_TOKEN. You may experience rate limits from GitHub"
-            );
-            "".to_string()
+    match &store_config {
+        StoreConfig::Redis(RedisConfig::Standalone(_)) => info!("Storing data in Redis."),
+        StoreConfig::Redis(RedisConfig::Sentinel { master, .. }) => {
+            info!(master, "Storing data in Redis behind Sentinel.")
         }
+        StoreConfig::Redis(RedisConfig::Cluster(nodes)) => {
+            info!(nodes = nodes.len(), "Storing data in Redis Cluster.")
+        }
+        StoreConfig::Sqlite(path) => info!(path, "No REDIS_URL given. Storing data in SQLite."),
+        StoreConfig::Memory(_) => warn!("Keeping data in memory. It is lost on exit."),
+    }
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => panic!("Invalid configuration: {}", e),
     };
+    if config.github_token.is_empty() {
+        warn!("You do not provide GITHUB_TOKEN. You may experience rate limits from GitHub");
+    }
+    let github_token = Arc::new(Mutex::new(config.github_token.clone()));
+    set_config(config);
     let port = match env::var("PORT") {
         Ok(val) => val,
         Err(_e) => "8080".to_string(),
     }
     .parse::<u16>()
     .unwrap();
+    let repo_url = repo_url();
+    let clone_mode = match CloneMode::from_env() {
+        Ok(clone_mode) => clone_mode,
+        Err(e) => panic!("Invalid clone configuration: {}", e),
+    };
+    // Bundles hold every object, so a partial clone of one is a bare clone.
+    let clone_mode = match clone_mode.filter() {
+        Some(_) if bundle_path(&repo_url).is_some() => {
+            warn!("REPO_URL is a bundle, which cannot be cloned partially. Cloning bare instead.");
+            CloneMode::Bare
+        }
+        _ => clone_mode,
+    };
+    // How long in-flight HTTP requests may take to finish once we are asked to stop.
+    let shutdown_timeout = match env::var("SHUTDOWN_TIMEOUT") {
+        Ok(val) => val,
+        Err(_e) => "30".to_string(),
+    }
+    .parse::<u64>()
+    .unwrap();
+
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("Error setting signal handler");
+    let signal_github_token = github_token.clone();
+    let signal_indexer_tx = indexer_tx.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                info!("Received SIGHUP. Reloading configuration...");
+                let _ = reload_config(&signal_github_token, &signal_indexer_tx);
+                continue;
+            }
+            info!(signal, "Received termination signal. Shutting down...");
+            request_shutdown();
+            signal_indexer_tx
+                .send(IndexerCommand::Shutdown)
+                .expect("Could not send signal on channel.");
+            break;
+        }
+    });
+
+    let notifier_store_config = store_config.clone();
+    let notifier_github_token = github_token.clone();
+    let notifier_events = subscribe_index_events();
+    thread::spawn(move || {
+        run_notifier(
+            notifier_store_config,
+            notifier_github_token,
+            notifier_events,
+        )
+    });
 
-    ctrlc::set_handler(move || tx.send(()).expect("Could not send signal on channel."))
-        .expect("Error setting Ctrl-C handler");
+    if let Some(dir) = bundle_dir() {
+        info!(dir = %dir.display(), "Updating from bundles instead of fetching.");
+        let bundle_indexer_tx = indexer_tx.clone();
+        thread::spawn(move || watch_bundles(dir, bundle_indexer_tx));
+    }
 
-    let cloned_redis_url = redis_url.clone();
-    let handler = thread::spawn(move || server(cloned_redis_url, port, github_token));
-    let mut con = open_redis_connection(redis_url.clone()).unwrap().unwrap();
-    let _ = set_index_state(&mut con, IndexState::Starting);
-    println!("Trying to open existing git repo...");
-    let repo = match Repository::open(REPO_PATH) {
-        Ok(repo) => repo,
-        Err(_e) => {
-            let _ = set_index_state(&mut con, IndexState::CloningGitRepo);
-            println!("No valid git repo found. Cloning....");
-            match Repository::clone(URL, REPO_PATH) {
-                Ok(repo) => repo,
-                Err(e) => panic!("failed to clone: {}", e),
+    let (server_handle_tx, server_handle_rx) = channel();
+    let server_store_config = store_config.clone();
+    let handler = thread::spawn(move || {
+        server(
+            server_store_config,
+            port,
+            github_token,
+            indexer_tx,
+            shutdown_timeout,
+            server_handle_tx,
+        )
+    });
+    let server_handle = server_handle_rx
+        .recv()
+        .expect("Web server exited before it started.");
+    let mut store = store_config
+        .open()
+        .unwrap_or_else(|e| panic!("Failed to open the store: {}", e));
+    let _ = store.set_index_state(IndexState::Starting);
+    let mut repo = open_repo(store.as_mut(), &repo_url, clone_mode);
+    while let Some(current) = repo.filter(|_| !shutdown_requested()) {
+        info!("Successfully opened git repo.");
+        let _ = store.set_index_state(IndexState::IndexingCommit);
+        match run_scheduler(&current, store.as_mut(), &indexer_rx) {
+            Ok(()) => break,
+            // Opening it again finds the same problem and clones it again.
+            Err(_problem) => {
+                drop(current);
+                repo = open_repo(store.as_mut(), &repo_url, clone_mode);
             }
         }
-    };
-    println!("Successfully opened git repo.");
-    let _ = set_index_state(&mut con, IndexState::IndexingCommit);
-    index_redis(&repo, &mut con);
+    }
 
+    info!(
+        timeout = ?Duration::from_secs(shutdown_timeout),
+        "Draining HTTP connections..."
+    );
+    // The stop command is delivered as soon as `stop` is called; the server
+    // thread finishes once in-flight requests are done or the timeout hits.
+    drop(server_handle.stop(true));
     let _ = handler.join();
-    rx.recv().expect("Could not receive from channel.");
 
-    println!("Received SIGTERM kill signal. Exiting...");
+    info!("Shutdown complete. Exiting...");
     Ok(())
 }
 
-fn update_git_repo(repo: &Repository, branch: &str) {
-    let remote_name = "origin";
-    let remote_branch = branch;
-    let mut remote = repo.find_remote(remote_name).unwrap();
-    let fetch_commit = do_fetch(&repo, &[remote_branch], &mut remote).unwrap();
-    let _ = do_merge(&repo, &remote_branch, fetch_commit);
+// Checks an existing clone, pointing it at REPO_URL first if that changed.
+fn check_clone(repo: &Repository, repo_url: &str) -> Result<(), String> {
+    match point_origin(repo, repo_url) {
+        Ok(true) => info!(url = repo_url, "REPO_URL changed. Pointed origin at it."),
+        Ok(false) => {}
+        Err(e) => return Err(format!("Cannot point origin at REPO_URL: {}", e)),
+    }
+    check_repo(repo)
+}
+
+// Opens the clone at REPO_PATH, converting it to `clone_mode`, or clones it
+// when there is none. A clone that cannot be used is moved aside and cloned again.
+fn open_repo(store: &mut dyn Store, repo_url: &str, clone_mode: CloneMode) -> Option<Repository> {
+    info!(path = REPO_PATH, "Trying to open existing git repo...");
+    let mut cloning = IndexState::CloningGitRepo;
+    let existing = match Repository::open(REPO_PATH) {
+        Ok(repo) => match check_clone(&repo, repo_url) {
+            Ok(()) => Some(repo),
+            Err(problem) => {
+                drop(repo);
+                cloning = IndexState::RepairingGitRepo;
+                repair_repo(store, &problem);
+                None
+            }
+        },
+        Err(_e) if !Path::new(REPO_PATH).exists() => None,
+        Err(e) => {
+            cloning = IndexState::RepairingGitRepo;
+            repair_repo(store, &e.to_string());
+            None
+        }
+    };
+    match existing {
+        Some(repo) if CloneMode::of(&repo) == clone_mode => Some(repo),
+        Some(repo) => {
+            let _ = store.set_index_state(IndexState::CloningGitRepo);
+            info!(
+                from = ?CloneMode::of(&repo),
+                to = ?clone_mode,
+                "Git repo was cloned with another CLONE_MODE. Converting...."
+            );
+            let start = Instant::now();
+            match convert_repo(repo, repo_url, REPO_PATH, clone_mode) {
+                Ok(repo) => {
+                    info!(elapsed = ?start.elapsed(), "Converted git repo.");
+                    Some(repo)
+                }
+                Err(_e) if shutdown_requested() => {
+                    warn!("Conversion interrupted by shutdown.");
+                    None
+                }
+                Err(e) => {
+                    // Every mode can be indexed, so carry on with the old clone.
+                    error!(error = %e, "Failed to convert git repo. Keeping it as it is.");
+                    match Repository::open(REPO_PATH) {
+                        Ok(repo) => Some(repo),
+                        Err(e) => panic!("failed to reopen git repo: {}", e),
+                    }
+                }
+            }
+        }
+        None => {
+            let _ = store.set_index_state(cloning);
+            info!(url = repo_url, "No valid git repo found. Cloning....");
+            let start = Instant::now();
+            match clone_repo(repo_url, REPO_PATH, clone_mode) {
+                Ok(repo) => {
+                    info!(elapsed = ?start.elapsed(), "Cloned git repo.");
+                    Some(repo)
+                }
+                Err(_e) if shutdown_requested() => {
+                    warn!("Clone interrupted by shutdown.");
+                    None
+                }
+                Err(e) => {
+                    error!(error = %e, "Failed to clone git repo");
+                    panic!("failed to clone: {}", e)
+                }
+            }
+        }
+    }
+}
+
+fn repair_repo(store: &mut dyn Store, problem: &str) {
+    let _ = store.set_index_state(IndexState::RepairingGitRepo);
+    error!(
+        problem,
+        "Git repo is broken. Moving it aside to clone it again."
+    );
+    match move_aside(REPO_PATH) {
+        Ok(aside) => info!(path = aside, "Moved broken git repo aside."),
+        Err(e) => panic!("failed to move broken git repo aside: {}", e),
+    }
+}
+
+// Fetches every branch about to be indexed in a single round trip.
+fn fetch_branches(repo: &Repository, branches: &[String]) -> Result<FetchStats, Error> {
+    let _span = info_span!("fetch", branches = ?branches).entered();
+    let start = Instant::now();
+    let mut remote = repo.find_remote("origin")?;
+    info!("Fetching from remote");
+    let bundle = remote.url().and_then(bundle_path);
+    let fetched = match (bundle_dir(), bundle) {
+        (Some(dir), _) => fetch_bundles(repo, branches, &dir),
+        // Seeded from a bundle: pick up a newer bundle left at the same path.
+        (None, Some(bundle)) => fetch_bundle(repo, branches, &bundle),
+        (None, None) if CloneMode::of(repo).filter().is_some() => {
+            fetch_filtered(repo, branches, &mut remote)
+        }
+        (None, None) => do_fetch(repo, branches, &mut remote),
+    };
+    match &fetched {
+        Ok(stats) => info!(
+            elapsed = ?start.elapsed(),
+            received = stats.received_objects,
+            indexed = stats.indexed_objects,
+            local = stats.local_objects,
+            bytes = stats.received_bytes,
+            updated = ?stats.updated,
+            pruned = ?stats.pruned,
+            "Fetch finished"
+        ),
+        Err(_e) if shutdown_requested() => warn!("Fetch interrupted by shutdown."),
+        Err(e) => error!(error = %e, "Failed to fetch from remote"),
+    }
+    fetched
+}
+
+// Brings the local branch up to the fetched remote-tracking ref.
+fn update_git_repo(repo: &Repository, branch: &str) -> Result<(), Error> {
+    let tracking = repo.find_reference(&remote_tracking_ref(branch))?;
+    let fetch_commit = repo.reference_to_annotated_commit(&tracking)?;
+    let updated = match repo.is_bare() {
+        true => follow_branch(repo, branch, &fetch_commit),
+        false => do_merge(repo, branch, fetch_commit),
+    };
+    if let Err(e) = &updated {
+        error!(error = %e, "Failed to merge fetched commits");
+    }
+    updated
 }
 
 fn switch_branch(refname: &str, repo: &Repository) -> Result<(), Error> {
-    let (object, reference) = repo.revparse_ext(refname).expect("Object not found");
+    let (object, reference) = repo.revparse_ext(refname)?;
     let mut binding = CheckoutBuilder::new();
     let checkout_builder = binding.force();
-    repo.checkout_tree(&object, Some(checkout_builder))
-        .expect("Failed to checkout");
+    repo.checkout_tree(&object, Some(checkout_builder))?;
     match reference {
         // gref is an actual reference like branches or tags
         Some(gref) => repo.set_head(gref.name().unwrap()),
         // this is a commit, not a reference
         None => repo.set_head_detached(object.id()),
     }
-    .expect("Failed to set HEAD");
-    Ok(())
 }
@@ -0,0 +1,175 @@
+use std::{
+    fmt::Write,
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use git2::Repository;
+use serde::Serialize;
+use tracing::{error, info, info_span, warn};
+
+use crate::{config::current_config, pull::run_git, shutdown::shutdown_requested};
+
+// File in the git dir whose modification time is the last maintenance run, so
+// that restarts do not reset the schedule.
+const STAMP: &str = "tracker-maintenance";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MaintenanceRun {
+    pub started_at: i64,
+    pub elapsed_seconds: f64,
+    // Size of the object database in bytes.
+    pub size_before: u64,
+    pub size_after: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MaintenanceStatus {
+    pub runs: u64,
+    pub failures: u64,
+    pub last: Option<MaintenanceRun>,
+}
+
+static STATUS: Mutex<MaintenanceStatus> = Mutex::new(MaintenanceStatus {
+    runs: 0,
+    failures: 0,
+    last: None,
+});
+
+pub fn maintenance_status() -> MaintenanceStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+            _ => entry.metadata().map_or(0, |metadata| metadata.len()),
+        })
+        .sum()
+}
+
+// A clone without a stamp was just made, or predates maintenance: start the
+// schedule from now.
+fn due(repo: &Repository, interval: Duration) -> bool {
+    let stamp = repo.path().join(STAMP);
+    match fs::metadata(&stamp).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified.elapsed().unwrap_or_default() >= interval,
+        Err(_e) => {
+            let _ = fs::write(&stamp, "");
+            false
+        }
+    }
+}
+
+fn maintain(repo: &Repository) -> Result<(), git2::Error> {
+    let git_dir = format!("--git-dir={}", repo.path().display());
+    // Every fetch leaves a pack behind. A geometric repack rolls the small ones
+    // and loose objects up without rewriting all of history every time.
+    run_git(&[&git_dir, "repack", "-d", "-l", "--geometric=2"])?;
+    run_git(&[&git_dir, "prune", "--expire=2.weeks.ago"])?;
+    run_git(&[&git_dir, "commit-graph", "write", "--reachable"])
+}
+
+// Runs git maintenance when MAINTENANCE_INTERVAL has passed since the last
+// run. Called by the indexer between passes, so it never overlaps one.
+pub fn maintain_if_due(repo: &Repository) {
+    let interval = current_config().maintenance_interval;
+    if interval == 0 || !due(repo, Duration::from_secs(interval)) {
+        return;
+    }
+    let _span = info_span!("maintenance").entered();
+    let objects = repo.path().join("objects");
+    let size_before = directory_size(&objects);
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let start = Instant::now();
+    info!(size_before, "Running git maintenance...");
+    let result = maintain(repo);
+    if shutdown_requested() {
+        warn!("Git maintenance interrupted by shutdown.");
+        return;
+    }
+    // A failing step is retried after a whole interval, not on every pass.
+    let _ = fs::write(repo.path().join(STAMP), "");
+    let run = MaintenanceRun {
+        started_at,
+        elapsed_seconds: start.elapsed().as_secs_f64(),
+        size_before,
+        size_after: directory_size(&objects),
+        error: result.err().map(|e| e.message().to_string()),
+    };
+    match &run.error {
+        None => info!(
+            elapsed = ?start.elapsed(),
+            size_before,
+            size_after = run.size_after,
+            "Finished git maintenance."
+        ),
+        Some(e) => error!(error = e, "Git maintenance failed."),
+    }
+    let mut status = STATUS.lock().unwrap();
+    status.runs += 1;
+    if run.error.is_some() {
+        status.failures += 1;
+    }
+    status.last = Some(run);
+}
+
+// Maintenance figures in the Prometheus text format.
+pub fn maintenance_metrics() -> String {
+    let status = maintenance_status();
+    let mut metrics = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, f64)]| {
+        let _ = writeln!(metrics, "# HELP {} {}", name, help);
+        let _ = writeln!(metrics, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(metrics, "{}{} {}", name, labels, value);
+        }
+    };
+    metric(
+        "tracker_git_maintenance_runs_total",
+        "counter",
+        "Git maintenance runs since start.",
+        &[("", status.runs as f64)],
+    );
+    metric(
+        "tracker_git_maintenance_failures_total",
+        "counter",
+        "Git maintenance runs that failed since start.",
+        &[("", status.failures as f64)],
+    );
+    if let Some(last) = &status.last {
+        metric(
+            "tracker_git_maintenance_last_run_timestamp_seconds",
+            "gauge",
+            "When the last git maintenance run started.",
+            &[("", last.started_at as f64)],
+        );
+        metric(
+            "tracker_git_maintenance_last_duration_seconds",
+            "gauge",
+            "How long the last git maintenance run took.",
+            &[("", last.elapsed_seconds)],
+        );
+        metric(
+            "tracker_git_objects_size_bytes",
+            "gauge",
+            "Size of the git object database around the last maintenance run.",
+            &[
+                ("{phase=\"before\"}", last.size_before as f64),
+                ("{phase=\"after\"}", last.size_after as f64),
+            ],
+        );
+    }
+    metrics
+}
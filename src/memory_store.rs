@@ -0,0 +1,328 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use git2::Oid;
+
+use crate::{
+    config::IndexState,
+    eta::{Advance, HISTORY_MAX_ENTRIES, LAG_MAX_SAMPLES},
+    feed::{FeedEntry, FEED_MAX_ENTRIES},
+    store::{Store, StoreResult},
+    watch::Watch,
+};
+
+#[derive(Default, Debug)]
+pub struct MemoryData {
+    state: String,
+    tips: HashMap<String, String>,
+    // Keyed by branch and whether it is the delta being built.
+    branch_commits: HashMap<(String, bool), HashSet<Oid>>,
+    reachable: HashMap<String, HashMap<String, String>>,
+    pr_merges: HashMap<u64, String>,
+    merges_scanned: HashSet<String>,
+    feeds: HashMap<String, VecDeque<FeedEntry>>,
+    histories: HashMap<String, VecDeque<Advance>>,
+    arrivals: HashMap<String, HashMap<String, i64>>,
+    lags: HashMap<(String, String), VecDeque<i64>>,
+    first_parents: HashMap<String, BTreeMap<usize, String>>,
+    watches: HashMap<String, Watch>,
+}
+
+// Keeps everything in the process and loses it on exit. Every store opened
+// from the same `StoreConfig::Memory` shares one `MemoryData`.
+pub struct MemoryStore {
+    data: Arc<Mutex<MemoryData>>,
+}
+
+// Newest first, like the Redis lists.
+fn push_newest<T>(list: &mut VecDeque<T>, values: impl IntoIterator<Item = T>, max: usize) {
+    for value in values {
+        list.push_front(value);
+    }
+    list.truncate(max);
+}
+
+impl MemoryStore {
+    pub fn open(data: Arc<Mutex<MemoryData>>) -> MemoryStore {
+        MemoryStore { data }
+    }
+
+    fn data(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap()
+    }
+}
+
+impl Store for MemoryStore {
+    fn index_state(&mut self) -> StoreResult<String> {
+        Ok(self.data().state.clone())
+    }
+
+    fn set_index_state(&mut self, state: IndexState) -> StoreResult<()> {
+        self.data().state = state.as_str().to_string();
+        Ok(())
+    }
+
+    fn branch_tip(&mut self, branch: &str) -> StoreResult<Option<String>> {
+        Ok(self.data().tips.get(branch).cloned())
+    }
+
+    fn set_branch_tip(&mut self, branch: &str, tip: &str) -> StoreResult<()> {
+        self.data().tips.insert(branch.to_string(), tip.to_string());
+        Ok(())
+    }
+
+    fn reset_branch(&mut self, branch: &str) -> StoreResult<()> {
+        let mut data = self.data();
+        data.tips.remove(branch);
+        data.branch_commits.remove(&(branch.to_string(), true));
+        data.first_parents.remove(branch);
+        data.reachable.remove(branch);
+        Ok(())
+    }
+
+    fn add_branch_commits(
+        &mut self,
+        branch: &str,
+        delta: bool,
+        commits: &[Oid],
+    ) -> StoreResult<()> {
+        self.data()
+            .branch_commits
+            .entry((branch.to_string(), delta))
+            .or_default()
+            .extend(commits);
+        Ok(())
+    }
+
+    fn finish_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()> {
+        if !delta {
+            return Ok(());
+        }
+        let mut data = self.data();
+        let commits = data
+            .branch_commits
+            .remove(&(branch.to_string(), true))
+            .unwrap_or_default();
+        data.branch_commits
+            .insert((branch.to_string(), false), commits);
+        Ok(())
+    }
+
+    fn discard_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()> {
+        self.data()
+            .branch_commits
+            .remove(&(branch.to_string(), delta));
+        Ok(())
+    }
+
+    fn branch_inclusion(
+        &mut self,
+        branches: &[String],
+        commits: &[String],
+    ) -> StoreResult<Vec<bool>> {
+        let data = self.data();
+        Ok(branches
+            .iter()
+            .map(|branch| {
+                let live = data.branch_commits.get(&(branch.clone(), false));
+                commits
+                    .iter()
+                    .all(|commit| match (live, Oid::from_str(commit)) {
+                        (Some(live), Ok(oid)) => live.contains(&oid),
+                        _ => false,
+                    })
+            })
+            .collect())
+    }
+
+    fn reachable(&mut self, branch: &str, commit: &str) -> StoreResult<Option<String>> {
+        Ok(self
+            .data()
+            .reachable
+            .get(branch)
+            .and_then(|answers| answers.get(commit))
+            .cloned())
+    }
+
+    fn save_reachable(&mut self, branch: &str, commit: &str, answer: &str) -> StoreResult<()> {
+        self.data()
+            .reachable
+            .entry(branch.to_string())
+            .or_default()
+            .insert(commit.to_string(), answer.to_string());
+        Ok(())
+    }
+
+    fn clear_reachable(&mut self, branch: &str) -> StoreResult<()> {
+        self.data().reachable.remove(branch);
+        Ok(())
+    }
+
+    fn forget_unreachable(&mut self, branch: &str) -> StoreResult<()> {
+        if let Some(answers) = self.data().reachable.get_mut(branch) {
+            answers.retain(|_, answer| answer == "1");
+        }
+        Ok(())
+    }
+
+    fn save_pr_merges(&mut self, merges: &[(u64, String)]) -> StoreResult<()> {
+        self.data().pr_merges.extend(merges.iter().cloned());
+        Ok(())
+    }
+
+    fn pr_merge(&mut self, pr: u64) -> StoreResult<Option<String>> {
+        Ok(self.data().pr_merges.get(&pr).cloned())
+    }
+
+    fn merges_scanned(&mut self, branch: &str) -> StoreResult<bool> {
+        Ok(self.data().merges_scanned.contains(branch))
+    }
+
+    fn set_merges_scanned(&mut self, branch: &str) -> StoreResult<()> {
+        self.data().merges_scanned.insert(branch.to_string());
+        Ok(())
+    }
+
+    fn forget_pr_merge(&mut self, pr: u64) -> StoreResult<bool> {
+        Ok(self.data().pr_merges.remove(&pr).is_some())
+    }
+
+    fn feed(&mut self, branch: &str) -> StoreResult<Vec<FeedEntry>> {
+        Ok(self
+            .data()
+            .feeds
+            .get(branch)
+            .map(|feed| feed.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn push_feed_entries(&mut self, branch: &str, entries: &[FeedEntry]) -> StoreResult<()> {
+        let mut data = self.data();
+        let feed = data.feeds.entry(branch.to_string()).or_default();
+        push_newest(feed, entries.iter().cloned(), FEED_MAX_ENTRIES);
+        Ok(())
+    }
+
+    fn history(&mut self, branch: &str, count: usize) -> StoreResult<Vec<Advance>> {
+        Ok(self
+            .data()
+            .histories
+            .get(branch)
+            .map(|history| history.iter().take(count).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn push_history(&mut self, branch: &str, advance: &Advance) -> StoreResult<()> {
+        let mut data = self.data();
+        let history = data.histories.entry(branch.to_string()).or_default();
+        push_newest(history, [advance.clone()], HISTORY_MAX_ENTRIES);
+        Ok(())
+    }
+
+    fn arrival(&mut self, branch: &str, commit: &str) -> StoreResult<Option<i64>> {
+        Ok(self
+            .data()
+            .arrivals
+            .get(branch)
+            .and_then(|arrivals| arrivals.get(commit))
+            .copied())
+    }
+
+    fn save_arrivals(
+        &mut self,
+        branch: &str,
+        arrivals: &[(String, i64)],
+        oldest: i64,
+    ) -> StoreResult<()> {
+        let mut data = self.data();
+        let saved = data.arrivals.entry(branch.to_string()).or_default();
+        for (commit, at) in arrivals {
+            saved.entry(commit.clone()).or_insert(*at);
+        }
+        saved.retain(|_, at| *at > oldest);
+        Ok(())
+    }
+
+    fn lag_samples(&mut self, upstream: &str, branch: &str) -> StoreResult<Vec<i64>> {
+        Ok(self
+            .data()
+            .lags
+            .get(&(upstream.to_string(), branch.to_string()))
+            .map(|lags| lags.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    fn push_lag_samples(&mut self, upstream: &str, branch: &str, lags: &[i64]) -> StoreResult<()> {
+        let mut data = self.data();
+        let samples = data
+            .lags
+            .entry((upstream.to_string(), branch.to_string()))
+            .or_default();
+        push_newest(samples, lags.iter().copied(), LAG_MAX_SAMPLES);
+        Ok(())
+    }
+
+    fn first_parent_top(&mut self, branch: &str) -> StoreResult<Option<(String, usize)>> {
+        Ok(self
+            .data()
+            .first_parents
+            .get(branch)
+            .and_then(|parents| parents.last_key_value())
+            .map(|(position, commit)| (commit.clone(), *position)))
+    }
+
+    fn add_first_parents(
+        &mut self,
+        branch: &str,
+        start: usize,
+        commits: &[String],
+    ) -> StoreResult<()> {
+        let mut data = self.data();
+        let parents = data.first_parents.entry(branch.to_string()).or_default();
+        for (offset, commit) in commits.iter().enumerate() {
+            parents.insert(start + offset, commit.clone());
+        }
+        Ok(())
+    }
+
+    fn clear_first_parents(&mut self, branch: &str) -> StoreResult<()> {
+        self.data().first_parents.remove(branch);
+        Ok(())
+    }
+
+    fn first_parent_count(&mut self, branch: &str) -> StoreResult<usize> {
+        Ok(self
+            .data()
+            .first_parents
+            .get(branch)
+            .map_or(0, |parents| parents.len()))
+    }
+
+    fn first_parent_at(&mut self, branch: &str, position: usize) -> StoreResult<Option<String>> {
+        Ok(self
+            .data()
+            .first_parents
+            .get(branch)
+            .and_then(|parents| parents.get(&position))
+            .cloned())
+    }
+
+    fn save_watch(&mut self, watch: &Watch) -> StoreResult<()> {
+        self.data().watches.insert(watch.id.clone(), watch.clone());
+        Ok(())
+    }
+
+    fn watch(&mut self, id: &str) -> StoreResult<Option<Watch>> {
+        Ok(self.data().watches.get(id).cloned())
+    }
+
+    fn watches(&mut self) -> StoreResult<Vec<Watch>> {
+        Ok(self.data().watches.values().cloned().collect())
+    }
+
+    fn delete_watch(&mut self, id: &str) -> StoreResult<bool> {
+        Ok(self.data().watches.remove(id).is_some())
+    }
+}
@@ -0,0 +1,475 @@
+use git2::{Commit, Oid, Repository};
+use serde::{Deserialize, Serialize};
+
+use crate::github::MAX_LISTED_COMMITS;
+
+// A PR merge commit as created by GitHub's "Create a merge commit" button:
+//
+//     Merge pull request #12345 from owner/branch
+//
+//     Title of the PR
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MergedPr {
+    pub pr: u64,
+    pub commit: String,
+    pub title: String,
+    pub time: i64,
+}
+
+pub fn parse_merged_pr(message: &str) -> Option<u64> {
+    let rest = message.strip_prefix("Merge pull request #")?;
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if !rest[digits.len()..].starts_with(" from ") {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+pub fn merged_pr(commit: &Commit) -> Option<MergedPr> {
+    if commit.parent_count() < 2 {
+        return None;
+    }
+    let message = commit.message()?;
+    let pr = parse_merged_pr(message)?;
+    let title = message
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .to_string();
+    Some(MergedPr {
+        pr,
+        commit: commit.id().to_string(),
+        title,
+        time: commit.time().seconds(),
+    })
+}
+
+// PR merges reachable from `new_tip` but not from `old_tip`, oldest first.
+pub fn merged_prs_between(
+    repo: &Repository,
+    old_tip: Oid,
+    new_tip: Oid,
+) -> Result<Vec<MergedPr>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.push(new_tip)?;
+    revwalk.hide(old_tip)?;
+    let mut merges = vec![];
+    for commit_id in revwalk {
+        let commit = repo.find_commit(commit_id?)?;
+        if let Some(merge) = merged_pr(&commit) {
+            merges.push(merge);
+        }
+    }
+    Ok(merges)
+}
+
+// The commits a merge brought in: reachable from its second parent but not its first.
+pub fn pr_commits(repo: &Repository, merge_commit: &str) -> Result<Vec<String>, git2::Error> {
+    let merge = repo.find_commit(Oid::from_str(merge_commit)?)?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.push(merge.parent_id(1)?)?;
+    revwalk.hide(merge.parent_id(0)?)?;
+    // Same cap as the GitHub API, merges of whole branches can bring in thousands.
+    let commits: Vec<String> = revwalk
+        .take(MAX_LISTED_COMMITS)
+        .map(|commit_id| commit_id.map(|commit_id| commit_id.to_string()))
+        .collect::<Result<_, _>>()?;
+    if commits.len() >= MAX_LISTED_COMMITS {
+        return Err(git2::Error::from_str(&format!(
+            "PR has {} or more commits, more than GitHub lists",
+            MAX_LISTED_COMMITS
+        )));
+    }
+    Ok(commits)
+}
+
+fn contains(repo: &Repository, tip: Oid, commit: Oid) -> Result<bool, git2::Error> {
+    Ok(tip == commit || repo.graph_descendant_of(tip, commit)?)
+}
+
+// The oldest commit of an indexed first-parent chain of `len` commits that
+// contains `commit`. `chain_at` looks commits up by position, 0 being the root.
+pub fn first_containing(
+    repo: &Repository,
+    len: usize,
+    commit: Oid,
+    mut chain_at: impl FnMut(usize) -> Result<Oid, git2::Error>,
+) -> Result<Option<Oid>, git2::Error> {
+    if len == 0 || !contains(repo, chain_at(len - 1)?, commit)? {
+        return Ok(None);
+    }
+    let (mut low, mut high) = (0, len - 1);
+    while low < high {
+        let middle = (low + high) / 2;
+        if contains(repo, chain_at(middle)?, commit)? {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Ok(Some(chain_at(high)?))
+}
+
+// The first commit on `tip`'s first-parent history that contains `commit`, i.e.
+// the merge (or direct push) that made it reachable from the branch. Walks the
+// history, for branches whose first-parent chain is not indexed yet.
+pub fn landing_commit(
+    repo: &Repository,
+    tip: Oid,
+    commit: Oid,
+) -> Result<Option<Oid>, git2::Error> {
+    if !contains(repo, tip, commit)? {
+        return Ok(None);
+    }
+    // Containment only flips once along first-parent history, so probe at
+    // exponentially growing distances and bisect the last step. That keeps
+    // graph_descendant_of calls logarithmic in how far back the commit landed.
+    let mut chain = vec![tip];
+    let mut good = 0;
+    let mut probe = 1;
+    let mut bad = loop {
+        while chain.len() <= probe {
+            let last = repo.find_commit(chain[chain.len() - 1])?;
+            match last.parent_id(0) {
+                Ok(parent) => chain.push(parent),
+                Err(_) => break,
+            }
+        }
+        if chain.len() <= probe {
+            // Reached the root: the last commit of the chain is the landing commit
+            // unless it turns out not to contain the commit.
+            if contains(repo, chain[chain.len() - 1], commit)? {
+                return Ok(chain.last().copied());
+            }
+            break chain.len() - 1;
+        }
+        if !contains(repo, chain[probe], commit)? {
+            break probe;
+        }
+        good = probe;
+        probe *= 2;
+    };
+    while bad - good > 1 {
+        let middle = (good + bad) / 2;
+        if contains(repo, chain[middle], commit)? {
+            good = middle;
+        } else {
+            bad = middle;
+        }
+    }
+    Ok(Some(chain[good]))
+}
+
+// The staging-next merge into master that carried a PR, for PRs that went
+// through a staging cycle instead of being merged into master directly.
+#[derive(Serialize, Clone, Debug)]
+pub struct StagingCycle {
+    pub merge_commit: String,
+    // The staging-next PR, when the batch was merged through one.
+    pub pr: Option<u64>,
+    pub merged_at: i64,
+}
+
+// The branch a merge brought in, read from the subjects GitHub and git write:
+// "Merge pull request #N from owner/branch", "Merge branch 'branch' into x" and
+// "Merge remote-tracking branch 'origin/branch' into x".
+pub fn merged_branch(subject: &str) -> Option<&str> {
+    if subject.starts_with("Merge pull request #") {
+        let (_, head) = subject.split_once(" from ")?;
+        let (_owner, branch) = head.split_whitespace().next()?.split_once('/')?;
+        return Some(branch);
+    }
+    let rest = subject
+        .strip_prefix("Merge branch '")
+        .or_else(|| subject.strip_prefix("Merge remote-tracking branch '"))?;
+    let (branch, _) = rest.split_once('\'')?;
+    Some(branch.strip_prefix("origin/").unwrap_or(branch))
+}
+
+pub fn staging_cycle(
+    repo: &Repository,
+    landing: Oid,
+    commit: Oid,
+) -> Result<Option<StagingCycle>, git2::Error> {
+    if landing == commit {
+        return Ok(None);
+    }
+    let landing = repo.find_commit(landing)?;
+    let subject = landing.summary().unwrap_or_default();
+    if landing.parent_count() < 2 || merged_branch(subject) != Some("staging-next") {
+        return Ok(None);
+    }
+    // The commit has to come from the staging-next side of the merge.
+    if !contains(repo, landing.parent_id(1)?, commit)? {
+        return Ok(None);
+    }
+    Ok(Some(StagingCycle {
+        merge_commit: landing.id().to_string(),
+        pr: parse_merged_pr(subject),
+        merged_at: landing.time().seconds(),
+    }))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use git2::{Signature, Time};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // A repository built commit by commit. Trees are empty, only history matters.
+    pub(crate) struct Fixture {
+        _dir: TempDir,
+        pub(crate) repo: Repository,
+        clock: i64,
+    }
+
+    impl Fixture {
+        pub(crate) fn new() -> Fixture {
+            let dir = TempDir::new().unwrap();
+            let repo = Repository::init_bare(dir.path()).unwrap();
+            Fixture {
+                _dir: dir,
+                repo,
+                clock: 1_700_000_000,
+            }
+        }
+
+        pub(crate) fn commit(&mut self, parents: &[Oid], message: &str) -> Oid {
+            self.clock += 60;
+            let signature =
+                Signature::new("Fixture", "fixture@example.com", &Time::new(self.clock, 0))
+                    .unwrap();
+            let tree = self
+                .repo
+                .find_tree(self.repo.treebuilder(None).unwrap().write().unwrap())
+                .unwrap();
+            let parents: Vec<_> = parents
+                .iter()
+                .map(|parent| self.repo.find_commit(*parent).unwrap())
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+            self.repo
+                .commit(None, &signature, &signature, message, &tree, &parents)
+                .unwrap()
+        }
+
+        // Points HEAD at `branch`, force-moved to `commit` like a push would.
+        pub(crate) fn checkout(&self, branch: &str, commit: Oid) {
+            let name = format!("refs/heads/{}", branch);
+            self.repo.reference(&name, commit, true, "fixture").unwrap();
+            self.repo.set_head(&name).unwrap();
+        }
+    }
+
+    // master:       c0 - m1 ----- mn - m5
+    // PR #1:          \  /       /  \  /
+    //                  f1       /    f5    PR #5 from carol/fix-staging-next-eval
+    // staging-next:  c0 ----- sn           merged into master as PR #3
+    // staging:        c0 - s2 /
+    // PR #2:            \  /
+    //                    f2
+    struct Cycle {
+        fixture: Fixture,
+        f1: Oid,
+        m1: Oid,
+        s2: Oid,
+        mn: Oid,
+        f5: Oid,
+        m5: Oid,
+    }
+
+    fn cycle() -> Cycle {
+        let mut fixture = Fixture::new();
+        let c0 = fixture.commit(&[], "Initial commit");
+        let f1 = fixture.commit(&[c0], "hello: init at 1.0");
+        let m1 = fixture.commit(&[c0, f1], "Merge pull request #1 from alice/hello");
+        let f2 = fixture.commit(&[c0], "stdenv: rebuild the world");
+        let s2 = fixture.commit(&[c0, f2], "Merge pull request #2 from bob/stdenv");
+        let sn = fixture.commit(&[c0, s2], "Merge branch 'staging' into staging-next");
+        let mn = fixture.commit(&[m1, sn], "Merge pull request #3 from NixOS/staging-next");
+        let f5 = fixture.commit(&[mn], "ghc: fix staging-next eval");
+        let m5 = fixture.commit(
+            &[mn, f5],
+            "Merge pull request #5 from carol/fix-staging-next-eval",
+        );
+        Cycle {
+            fixture,
+            f1,
+            m1,
+            s2,
+            mn,
+            f5,
+            m5,
+        }
+    }
+
+    #[test]
+    fn staged_pr_landed_with_the_staging_next_merge() {
+        let cycle = cycle();
+        let repo = &cycle.fixture.repo;
+        assert_eq!(
+            landing_commit(repo, cycle.m5, cycle.s2).unwrap(),
+            Some(cycle.mn)
+        );
+        let staged = staging_cycle(repo, cycle.mn, cycle.s2).unwrap().unwrap();
+        assert_eq!(staged.merge_commit, cycle.mn.to_string());
+        assert_eq!(staged.pr, Some(3));
+    }
+
+    #[test]
+    fn pr_merged_into_master_has_no_staging_cycle() {
+        let cycle = cycle();
+        let repo = &cycle.fixture.repo;
+        assert_eq!(
+            landing_commit(repo, cycle.m5, cycle.m1).unwrap(),
+            Some(cycle.m1)
+        );
+        assert!(staging_cycle(repo, cycle.m1, cycle.m1).unwrap().is_none());
+        // Squashed or rebased commits land with the PR merge as well.
+        assert_eq!(
+            landing_commit(repo, cycle.m5, cycle.f1).unwrap(),
+            Some(cycle.m1)
+        );
+        assert!(staging_cycle(repo, cycle.m1, cycle.f1).unwrap().is_none());
+    }
+
+    #[test]
+    fn other_merges_naming_staging_next_are_no_staging_cycle() {
+        let cycle = cycle();
+        let repo = &cycle.fixture.repo;
+        assert_eq!(
+            landing_commit(repo, cycle.m5, cycle.f5).unwrap(),
+            Some(cycle.m5)
+        );
+        assert!(staging_cycle(repo, cycle.m5, cycle.f5).unwrap().is_none());
+        // Not carried by the staging-next merge it is compared to.
+        assert!(staging_cycle(repo, cycle.mn, cycle.f1).unwrap().is_none());
+    }
+
+    #[test]
+    fn merged_branches_are_read_from_merge_subjects() {
+        assert_eq!(
+            merged_branch("Merge pull request #3 from NixOS/staging-next"),
+            Some("staging-next")
+        );
+        assert_eq!(
+            merged_branch("Merge pull request #7 from alice/python/updates"),
+            Some("python/updates")
+        );
+        assert_eq!(
+            merged_branch("Merge branch 'staging-next' into master"),
+            Some("staging-next")
+        );
+        assert_eq!(
+            merged_branch("Merge remote-tracking branch 'origin/staging-next'"),
+            Some("staging-next")
+        );
+        assert_eq!(merged_branch("staging-next: fix eval"), None);
+    }
+
+    #[test]
+    fn first_containing_finds_the_landing_position() {
+        let mut cycle = cycle();
+        let c0 = cycle
+            .fixture
+            .repo
+            .find_commit(cycle.m1)
+            .unwrap()
+            .parent_id(0)
+            .unwrap();
+        let chain = [c0, cycle.m1, cycle.mn, cycle.m5];
+        let repo = &cycle.fixture.repo;
+        let first = |commit| first_containing(repo, chain.len(), commit, |i| Ok(chain[i])).unwrap();
+        assert_eq!(first(c0), Some(c0));
+        assert_eq!(first(cycle.f1), Some(cycle.m1));
+        assert_eq!(first(cycle.s2), Some(cycle.mn));
+        assert_eq!(first(cycle.f5), Some(cycle.m5));
+        assert_eq!(first(cycle.m5), Some(cycle.m5));
+        assert_eq!(
+            first_containing(repo, 0, cycle.f1, |i| Ok(chain[i])).unwrap(),
+            None
+        );
+        let unmerged = cycle.fixture.commit(&[c0], "hello: 2.0");
+        let repo = &cycle.fixture.repo;
+        assert_eq!(
+            first_containing(repo, chain.len(), unmerged, |i| Ok(chain[i])).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn first_containing_bisects_long_chains() {
+        let mut fixture = Fixture::new();
+        let mut chain = vec![fixture.commit(&[], "Initial commit")];
+        let feature = fixture.commit(&[chain[0]], "hello: init at 1.0");
+        for i in 1..100 {
+            let parents = match i {
+                37 => vec![chain[i - 1], feature],
+                _ => vec![chain[i - 1]],
+            };
+            chain.push(fixture.commit(&parents, &format!("Commit {}", i)));
+        }
+        let mut lookups = 0;
+        let found = first_containing(&fixture.repo, chain.len(), feature, |i| {
+            lookups += 1;
+            Ok(chain[i])
+        })
+        .unwrap();
+        assert_eq!(found, Some(chain[37]));
+        assert!(lookups <= 9, "{} lookups", lookups);
+        for (i, commit) in chain.iter().enumerate() {
+            let found = first_containing(&fixture.repo, chain.len(), *commit, |i| Ok(chain[i]));
+            assert_eq!(found.unwrap(), Some(chain[i]));
+        }
+    }
+
+    #[test]
+    fn pr_commits_beyond_what_github_lists_are_an_error() {
+        let mut fixture = Fixture::new();
+        let c0 = fixture.commit(&[], "Initial commit");
+        let mut head = c0;
+        for i in 1..MAX_LISTED_COMMITS {
+            head = fixture.commit(&[head], &format!("Commit {}", i));
+        }
+        let listed = fixture.commit(&[c0, head], "Merge pull request #1 from alice/big");
+        let commits = pr_commits(&fixture.repo, &listed.to_string()).unwrap();
+        assert_eq!(commits.len(), MAX_LISTED_COMMITS - 1);
+        assert_eq!(commits.last(), Some(&head.to_string()));
+
+        head = fixture.commit(&[head], "One too many");
+        let unlisted = fixture.commit(&[c0, head], "Merge pull request #2 from alice/bigger");
+        assert!(pr_commits(&fixture.repo, &unlisted.to_string()).is_err());
+    }
+
+    #[test]
+    fn merge_subjects_name_the_pr() {
+        assert_eq!(
+            parse_merged_pr("Merge pull request #123456 from alice/hello"),
+            Some(123456)
+        );
+        assert_eq!(
+            parse_merged_pr("Merge pull request #1 from alice/hello\n\nhello: init at 1.0"),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn other_subjects_name_no_pr() {
+        for message in [
+            "hello: 1.0 -> 1.1 (#3)",
+            "Merge branch 'master' into staging-next",
+            "Merge pull request #12",
+            "Merge pull request # from alice/hello",
+            "Merge pull request #12a from alice/hello",
+            "Revert \"Merge pull request #12 from alice/hello\"",
+            "merge pull request #12 from alice/hello",
+        ] {
+            assert_eq!(parse_merged_pr(message), None, "{}", message);
+        }
+    }
+}
@@ -1,66 +1,487 @@
-use std::io::{self, Write};
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use git2::Repository;
+use git2::{build::RepoBuilder, Oid, Repository};
+use tracing::{debug, info, instrument, warn};
 
-pub fn do_fetch<'a>(
-    repo: &'a git2::Repository,
-    refs: &[&str],
-    remote: &'a mut git2::Remote,
-) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+use crate::shutdown::shutdown_requested;
+
+// How nixpkgs is kept on disk. Indexing only reads commits, so everything but
+// `Full` skips the working tree, and the partial clones also leave out blobs
+// or trees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloneMode {
+    Full,
+    Bare,
+    Blobless,
+    Treeless,
+}
+
+impl CloneMode {
+    pub fn from_env() -> Result<CloneMode, String> {
+        match env::var("CLONE_MODE").as_deref() {
+            Ok("full") | Err(_) => Ok(CloneMode::Full),
+            Ok("bare") => Ok(CloneMode::Bare),
+            Ok("blobless") => Ok(CloneMode::Blobless),
+            Ok("treeless") => Ok(CloneMode::Treeless),
+            Ok(mode) => Err(format!(
+                "Invalid CLONE_MODE {}, expected full, bare, blobless or treeless",
+                mode
+            )),
+        }
+    }
+
+    // The mode an existing repository was cloned with.
+    pub fn of(repo: &Repository) -> CloneMode {
+        if !repo.is_bare() {
+            return CloneMode::Full;
+        }
+        let filter = repo
+            .config()
+            .and_then(|config| config.get_string("remote.origin.partialclonefilter"))
+            .unwrap_or_default();
+        match filter.as_str() {
+            "blob:none" => CloneMode::Blobless,
+            "tree:0" => CloneMode::Treeless,
+            _ => CloneMode::Bare,
+        }
+    }
+
+    pub fn filter(&self) -> Option<&'static str> {
+        match self {
+            CloneMode::Blobless => Some("blob:none"),
+            CloneMode::Treeless => Some("tree:0"),
+            CloneMode::Full | CloneMode::Bare => None,
+        }
+    }
+}
+
+// Runs the git executable for what libgit2 cannot do: filtered packs for
+// partial clones, bundles and maintenance. It is killed on shutdown, like an
+// aborted libgit2 transfer.
+pub fn run_git(args: &[&str]) -> Result<(), git2::Error> {
+    let failed = |e: std::io::Error| git2::Error::from_str(&format!("Failed to run git: {}", e));
+    let mut child = Command::new("git")
+        .args(args)
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(failed)?;
+    loop {
+        if let Some(status) = child.try_wait().map_err(failed)? {
+            return match status.success() {
+                true => Ok(()),
+                false => Err(git2::Error::from_str(&format!(
+                    "git {} failed with {}",
+                    args.join(" "),
+                    status
+                ))),
+            };
+        }
+        if shutdown_requested() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(git2::Error::from_str("git cancelled by shutdown"));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Like `run_git`, for quick commands whose output is needed.
+fn git_output(args: &[&str]) -> Result<String, git2::Error> {
+    let output = Command::new("git")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| git2::Error::from_str(&format!("Failed to run git: {}", e)))?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => Err(git2::Error::from_str(&format!(
+            "git {} failed with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+// The path of a local git bundle file, which libgit2 cannot read, so it is
+// cloned and fetched with the git executable.
+pub fn bundle_path(url: &str) -> Option<PathBuf> {
+    let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+    path.is_file().then(|| path.to_path_buf())
+}
+
+#[instrument(skip_all, fields(url, mode = ?mode))]
+pub fn clone_repo(url: &str, path: &str, mode: CloneMode) -> Result<Repository, git2::Error> {
+    if let Some(bundle) = bundle_path(url) {
+        let bundle = bundle.to_string_lossy();
+        let mut args = vec!["clone", "--quiet"];
+        if mode != CloneMode::Full {
+            args.push("--bare");
+        }
+        args.extend([&*bundle, path]);
+        run_git(&args)?;
+        return Repository::open(path);
+    }
+    if let Some(filter) = mode.filter() {
+        let filter = format!("--filter={}", filter);
+        run_git(&["clone", "--quiet", "--bare", &filter, url, path])?;
+        return Repository::open(path);
+    }
     let mut cb = git2::RemoteCallbacks::new();
+    // Returning false aborts the transfer, which lets a shutdown interrupt the initial clone.
+    cb.transfer_progress(|_stats| !shutdown_requested());
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(cb);
+    RepoBuilder::new()
+        .bare(mode == CloneMode::Bare)
+        .fetch_options(fo)
+        .clone(url, Path::new(path))
+}
+
+// How many first-parent commits of every branch are read back when checking a clone.
+const CHECK_DEPTH: usize = 100;
+
+// The same repository, allowing for local paths written differently.
+fn same_url(a: &str, b: &str) -> bool {
+    let local = |url: &str| fs::canonicalize(url.strip_prefix("file://").unwrap_or(url)).ok();
+    a == b || matches!((local(a), local(b)), (Some(a), Some(b)) if a == b)
+}
+
+// Points origin at REPO_URL when it changed since the clone was made, which is
+// cheaper than cloning again: a mirror or a fork shares most objects, and the
+// next fetch brings in the rest. Returns whether it changed.
+pub fn point_origin(repo: &Repository, url: &str) -> Result<bool, git2::Error> {
+    let remote = repo.find_remote("origin")?;
+    if same_url(remote.url().unwrap_or_default(), url) {
+        return Ok(false);
+    }
+    repo.remote_set_url("origin", url)?;
+    Ok(true)
+}
+
+// Finds what makes a clone unusable: a clone killed half way through has no
+// branches, and a lost or damaged object makes every fetch fail.
+#[instrument(skip_all)]
+pub fn check_repo(repo: &Repository) -> Result<(), String> {
+    repo.find_remote("origin")
+        .map_err(|e| format!("No origin remote: {}", e))?;
+    // A bare clone points HEAD at the branch being indexed, which may not exist yet.
+    if !repo.is_bare() {
+        repo.head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|e| format!("HEAD does not resolve: {}", e))?;
+    }
+    let mut branches = 0;
+    for reference in repo.references().map_err(|e| e.to_string())? {
+        let reference = reference.map_err(|e| format!("Unreadable ref: {}", e))?;
+        let name = String::from_utf8_lossy(reference.name_bytes()).into_owned();
+        let resolved = reference
+            .resolve()
+            .map_err(|e| format!("{} does not resolve: {}", name, e))?;
+        if !reference.is_branch() && !reference.is_remote() {
+            resolved
+                .peel(git2::ObjectType::Any)
+                .map_err(|e| format!("{} points to an unreadable object: {}", name, e))?;
+            continue;
+        }
+        branches += 1;
+        let tip = resolved
+            .peel_to_commit()
+            .map_err(|e| format!("{} points to an unreadable commit: {}", name, e))?;
+        let unreadable = |e: git2::Error| format!("History of {} is unreadable: {}", name, e);
+        let mut revwalk = repo.revwalk().map_err(unreadable)?;
+        revwalk.simplify_first_parent().map_err(unreadable)?;
+        revwalk.push(tip.id()).map_err(unreadable)?;
+        for commit_id in revwalk.take(CHECK_DEPTH) {
+            repo.find_commit(commit_id.map_err(unreadable)?)
+                .map_err(unreadable)?;
+        }
+    }
+    match branches {
+        0 => Err("No branches. The clone did not finish".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Keeps a broken clone next to the new one for inspection. Only the latest is
+// kept, as every copy is as large as the clone.
+pub fn move_aside(path: &str) -> Result<String, git2::Error> {
+    let path = Path::new(path);
+    let prefix = format!("{}.corrupt-", path.file_name().unwrap().to_string_lossy());
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    for entry in fs::read_dir(parent).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            fs::remove_dir_all(entry.path()).map_err(io_error)?;
+            info!(path = %entry.path().display(), "Removed older broken git repo.");
+        }
+    }
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let aside = format!("{}.corrupt-{}", path.display(), at);
+    fs::rename(path, &aside).map_err(io_error)?;
+    Ok(aside)
+}
+
+fn io_error(e: std::io::Error) -> git2::Error {
+    git2::Error::from_str(&e.to_string())
+}
 
-    // Print out our transfer progress.
-    cb.transfer_progress(|stats| {
-        if stats.received_objects() == stats.total_objects() {
-            print!(
-                "Resolving deltas {}/{}\r",
-                stats.indexed_deltas(),
-                stats.total_deltas()
-            );
-        } else if stats.total_objects() > 0 {
-            print!(
-                "Received {}/{} objects ({}) in {} bytes\r",
-                stats.received_objects(),
-                stats.total_objects(),
-                stats.indexed_objects(),
-                stats.received_bytes()
-            );
-        }
-        io::stdout().flush().unwrap();
+// Brings an existing clone to `mode`. A full clone becomes bare in place by
+// dropping its working tree. Anything else needs a fresh clone next to the
+// old one, which only replaces it once complete.
+#[instrument(skip_all, fields(from = ?CloneMode::of(&repo), to = ?mode))]
+pub fn convert_repo(
+    repo: Repository,
+    url: &str,
+    path: &str,
+    mode: CloneMode,
+) -> Result<Repository, git2::Error> {
+    if CloneMode::of(&repo) == CloneMode::Full && mode == CloneMode::Bare {
+        let git_dir = repo.path().to_path_buf();
+        drop(repo);
+        let converting = format!("{}.converting", path);
+        let _ = fs::remove_dir_all(&converting);
+        fs::rename(&git_dir, &converting).map_err(io_error)?;
+        git2::Config::open(&Path::new(&converting).join("config"))?.set_bool("core.bare", true)?;
+        fs::remove_dir_all(path).map_err(io_error)?;
+        fs::rename(&converting, path).map_err(io_error)?;
+        return Repository::open(path);
+    }
+    let fresh = format!("{}.new", path);
+    let _ = fs::remove_dir_all(&fresh);
+    match clone_repo(url, &fresh, mode) {
+        Ok(cloned) => drop(cloned),
+        Err(e) => {
+            let _ = fs::remove_dir_all(&fresh);
+            return Err(e);
+        }
+    }
+    drop(repo);
+    fs::remove_dir_all(path).map_err(io_error)?;
+    fs::rename(&fresh, path).map_err(io_error)?;
+    Repository::open(path)
+}
+
+// What one fetch brought in. The git executable does not report transfer
+// statistics, so these stay at zero for partial clones.
+#[derive(Debug, Default)]
+pub struct FetchStats {
+    pub received_objects: usize,
+    pub indexed_objects: usize,
+    pub local_objects: usize,
+    pub received_bytes: usize,
+    // Tracked branches whose remote-tracking ref moved or was removed because
+    // the branch is gone from the remote.
+    pub updated: Vec<String>,
+    pub pruned: Vec<String>,
+}
+
+impl FetchStats {
+    fn record_tips(&mut self, repo: &Repository, branches: &[String], before: Vec<Option<Oid>>) {
+        for (branch, before) in branches.iter().zip(before) {
+            match remote_tip(repo, branch) {
+                None if before.is_some() => self.pruned.push(branch.clone()),
+                after if after != before => self.updated.push(branch.clone()),
+                _ => {}
+            }
+        }
+    }
+}
+
+// Tags are of no use to indexing and nixpkgs has thousands of them.
+fn fetch_tags() -> bool {
+    matches!(env::var("FETCH_TAGS"), Ok(val) if val == "true")
+}
+
+pub fn remote_tracking_ref(branch: &str) -> String {
+    format!("refs/remotes/origin/{}", branch)
+}
+
+fn refspec(branch: &str) -> String {
+    format!("+refs/heads/{}:{}", branch, remote_tracking_ref(branch))
+}
+
+fn remote_tip(repo: &Repository, branch: &str) -> Option<Oid> {
+    repo.refname_to_id(&remote_tracking_ref(branch)).ok()
+}
+
+// Fetches into a partial clone with the git executable, which applies the
+// clone's filter to the new commits too.
+#[instrument(skip_all, fields(branches = ?branches))]
+pub fn fetch_filtered(
+    repo: &Repository,
+    branches: &[String],
+    remote: &mut git2::Remote,
+) -> Result<FetchStats, git2::Error> {
+    let before = branches
+        .iter()
+        .map(|branch| remote_tip(repo, branch))
+        .collect();
+    // git fails on refspecs of branches the remote does not have, and only
+    // prunes refs its refspecs match, so deleted branches are pruned here.
+    remote.connect(git2::Direction::Fetch)?;
+    let heads: HashSet<String> = remote
+        .list()?
+        .iter()
+        .map(|head| head.name().to_string())
+        .collect();
+    remote.disconnect()?;
+    let (present, gone): (Vec<&String>, Vec<&String>) = branches
+        .iter()
+        .partition(|branch| heads.contains(&format!("refs/heads/{}", branch)));
+    if !present.is_empty() {
+        let git_dir = format!("--git-dir={}", repo.path().display());
+        let tags = match fetch_tags() {
+            true => "--tags",
+            false => "--no-tags",
+        };
+        let refspecs: Vec<String> = present.iter().map(|branch| refspec(branch)).collect();
+        let mut args = vec![
+            git_dir.as_str(),
+            "fetch",
+            "--quiet",
+            "--prune",
+            tags,
+            "origin",
+        ];
+        args.extend(refspecs.iter().map(String::as_str));
+        run_git(&args)?;
+    }
+    for branch in gone {
+        if let Ok(mut tracking) = repo.find_reference(&remote_tracking_ref(branch)) {
+            tracking.delete()?;
+        }
+    }
+    let mut stats = FetchStats::default();
+    stats.record_tips(repo, branches, before);
+    Ok(stats)
+}
+
+// Fetches the tracked branches a bundle has. Bundles cannot tell deleted
+// branches apart from ones left out, so nothing is pruned.
+#[instrument(skip_all, fields(bundle = %bundle.display()))]
+pub fn fetch_bundle(
+    repo: &Repository,
+    branches: &[String],
+    bundle: &Path,
+) -> Result<FetchStats, git2::Error> {
+    let before = branches
+        .iter()
+        .map(|branch| remote_tip(repo, branch))
+        .collect();
+    let git_dir = format!("--git-dir={}", repo.path().display());
+    let bundle = bundle.to_string_lossy();
+    let heads = git_output(&[&git_dir, "bundle", "list-heads", &bundle])?;
+    let heads: HashSet<&str> = heads
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .collect();
+    let refspecs: Vec<String> = branches
+        .iter()
+        .filter(|branch| heads.contains(format!("refs/heads/{}", branch).as_str()))
+        .map(|branch| refspec(branch))
+        .collect();
+    if !refspecs.is_empty() {
+        let mut args = vec![git_dir.as_str(), "fetch", "--quiet", "--no-tags", &bundle];
+        args.extend(refspecs.iter().map(String::as_str));
+        run_git(&args)?;
+    }
+    let mut stats = FetchStats::default();
+    stats.record_tips(repo, branches, before);
+    Ok(stats)
+}
+
+// Fetches the given branches into their remote-tracking refs in one go.
+// Branches deleted on the remote lose their remote-tracking ref.
+#[instrument(skip_all, fields(remote = remote.name().unwrap_or_default(), branches = ?branches))]
+pub fn do_fetch(
+    repo: &Repository,
+    branches: &[String],
+    remote: &mut git2::Remote,
+) -> Result<FetchStats, git2::Error> {
+    let before = branches
+        .iter()
+        .map(|branch| remote_tip(repo, branch))
+        .collect();
+    let mut cb = git2::RemoteCallbacks::new();
+
+    // Report transfer progress, but only every 10% so that nixpkgs-sized
+    // fetches do not flood the log. Returning false aborts the fetch on shutdown.
+    let mut last_reported = None;
+    cb.transfer_progress(move |stats| {
+        if shutdown_requested() {
+            return false;
+        }
+        if stats.total_objects() == 0 {
+            return true;
+        }
+        let receiving = stats.received_objects() < stats.total_objects();
+        let percent = match receiving {
+            true => stats.received_objects() * 100 / stats.total_objects(),
+            false if stats.total_deltas() > 0 => {
+                stats.indexed_deltas() * 100 / stats.total_deltas()
+            }
+            false => 100,
+        } / 10
+            * 10;
+        if last_reported != Some((receiving, percent)) {
+            last_reported = Some((receiving, percent));
+            if receiving {
+                debug!(
+                    received = stats.received_objects(),
+                    total = stats.total_objects(),
+                    indexed = stats.indexed_objects(),
+                    bytes = stats.received_bytes(),
+                    "Receiving objects {}%",
+                    percent
+                );
+            } else {
+                debug!(
+                    indexed = stats.indexed_deltas(),
+                    total = stats.total_deltas(),
+                    "Resolving deltas {}%",
+                    percent
+                );
+            }
+        }
         true
     });
 
     let mut fo = git2::FetchOptions::new();
     fo.remote_callbacks(cb);
-    // Always fetch all tags.
-    // Perform a download and also update tips
-    fo.download_tags(git2::AutotagOption::All);
-    println!("Fetching {} for repo", remote.name().unwrap());
-    remote.fetch(refs, Some(&mut fo), None)?;
-
-    // If there are local objects (we got a thin pack), then tell the user
-    // how many objects we saved from having to cross the network.
-    let stats = remote.stats();
-    if stats.local_objects() > 0 {
-        println!(
-            "\rReceived {}/{} objects in {} bytes (used {} local \
-             objects)",
-            stats.indexed_objects(),
-            stats.total_objects(),
-            stats.received_bytes(),
-            stats.local_objects()
-        );
-    } else {
-        println!(
-            "\rReceived {}/{} objects in {} bytes",
-            stats.indexed_objects(),
-            stats.total_objects(),
-            stats.received_bytes()
-        );
-    }
+    fo.download_tags(match fetch_tags() {
+        true => git2::AutotagOption::All,
+        false => git2::AutotagOption::None,
+    });
+    fo.prune(git2::FetchPrune::On);
+    let refspecs: Vec<String> = branches.iter().map(|branch| refspec(branch)).collect();
+    remote.fetch(&refspecs, Some(&mut fo), None)?;
 
-    let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    Ok(repo.reference_to_annotated_commit(&fetch_head)?)
+    // If there are local objects (we got a thin pack), `local_objects` tells
+    // how many we saved from having to cross the network.
+    let transfer = remote.stats();
+    let mut stats = FetchStats {
+        received_objects: transfer.received_objects(),
+        indexed_objects: transfer.indexed_objects(),
+        local_objects: transfer.local_objects(),
+        received_bytes: transfer.received_bytes(),
+        ..FetchStats::default()
+    };
+    stats.record_tips(repo, branches, before);
+    Ok(stats)
 }
 
 pub fn fast_forward(
@@ -73,7 +494,7 @@ pub fn fast_forward(
         None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
     };
     let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-    println!("{}", msg);
+    info!("{}", msg);
     lb.set_target(rc.id(), &msg)?;
     repo.set_head(&name)?;
     repo.checkout_head(Some(
@@ -99,7 +520,7 @@ pub fn normal_merge(
     let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
 
     if idx.has_conflicts() {
-        println!("Merge conflicts detected...");
+        warn!("Merge conflicts detected...");
         repo.checkout_index(Some(&mut idx), None)?;
         return Ok(());
     }
@@ -133,7 +554,7 @@ pub fn do_merge<'a>(
 
     // 2. Do the appropriate merge
     if analysis.0.is_fast_forward() {
-        println!("Doing a fast forward");
+        debug!("Doing a fast forward");
         // do a fast forward
         let refname = format!("refs/heads/{}", remote_branch);
         match repo.find_reference(&refname) {
@@ -162,9 +583,26 @@ pub fn do_merge<'a>(
     } else if analysis.0.is_normal() {
         // do a normal merge
         let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        normal_merge(&repo, &head_commit, &fetch_commit)?;
+        normal_merge(repo, &head_commit, &fetch_commit)?;
     } else {
-        println!("Nothing to do...");
+        debug!("Nothing to do...");
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+// Bare clones have nothing to merge into: the local branch follows the
+// remote one, force pushes included, and HEAD points at it for indexing.
+pub fn follow_branch(
+    repo: &Repository,
+    branch: &str,
+    fetch_commit: &git2::AnnotatedCommit,
+) -> Result<(), git2::Error> {
+    let refname = format!("refs/heads/{}", branch);
+    repo.reference(
+        &refname,
+        fetch_commit.id(),
+        true,
+        &format!("Setting {} to {}", branch, fetch_commit.id()),
+    )?;
+    repo.set_head(&refname)
+}
@@ -0,0 +1,174 @@
+use std::sync::Mutex;
+
+use git2::{Oid, Repository};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    config::{current_config, REPO_PATH},
+    store::{Store, StoreError, StoreResult},
+};
+
+// How "is this commit in that branch" gets answered.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Reachability {
+    // Every branch keeps a bitmap of its whole history. Lookups never touch git,
+    // but memory grows with the number of branches.
+    Bitmap,
+    // Git is asked whether the branch tip descends from the commit, which uses
+    // commit-graph generation numbers when the repo has a commit-graph file.
+    // The store only keeps the tips and the answers.
+    Graph,
+}
+
+impl Reachability {
+    pub fn parse(value: &str) -> Result<Reachability, String> {
+        match value {
+            "bitmap" => Ok(Reachability::Bitmap),
+            "graph" => Ok(Reachability::Graph),
+            _ => Err(format!(
+                "Invalid REACHABILITY {}, expected bitmap or graph",
+                value
+            )),
+        }
+    }
+}
+
+fn commit_reachable(
+    repo: &Repository,
+    store: &Mutex<Box<dyn Store>>,
+    branch: &str,
+    tip: Oid,
+    commit: &str,
+) -> Result<bool, git2::Error> {
+    // A commit reachable from the tip stays reachable as long as the branch
+    // fast-forwards. Staging branches are force-pushed though: the indexer drops
+    // every answer when it finds first-parent history rewritten. A negative answer
+    // only holds for the tip it was given for, and is evicted when the tip moves.
+    let tip_hex = tip.to_string();
+    match store
+        .lock()
+        .unwrap()
+        .reachable(branch, commit)
+        .unwrap_or_default()
+    {
+        Some(cached) if cached == "1" => return Ok(true),
+        Some(cached) if cached == tip_hex => return Ok(false),
+        _ => {}
+    }
+    let reachable = match Oid::from_str(commit) {
+        // Commits of PRs that never landed anywhere are not even in the repo.
+        Ok(oid) if repo.find_commit(oid).is_ok() => {
+            tip == oid || repo.graph_descendant_of(tip, oid)?
+        }
+        _ => false,
+    };
+    let answer = match reachable {
+        true => "1",
+        false => &tip_hex,
+    };
+    let _ = store.lock().unwrap().save_reachable(branch, commit, answer);
+    Ok(reachable)
+}
+
+// For every branch, whether all of the given commits are reachable from its indexed tip.
+pub fn graph_inclusion(
+    repo: &Repository,
+    store: &Mutex<Box<dyn Store>>,
+    branches: &[String],
+    commits: &[String],
+) -> Result<Vec<bool>, git2::Error> {
+    let mut included = vec![];
+    for branch in branches {
+        let tip = store.lock().unwrap().branch_tip(branch).unwrap_or_default();
+        let Some(tip) = tip else {
+            included.push(false);
+            continue;
+        };
+        let tip = Oid::from_str(&tip)?;
+        let mut is_fully_included = true;
+        for commit in commits {
+            if !commit_reachable(repo, store, branch, tip, commit)? {
+                is_fully_included = false;
+                break;
+            }
+        }
+        included.push(is_fully_included);
+    }
+    Ok(included)
+}
+
+// Answers with whichever reachability engine is configured. May read the git
+// repository, so call it off the async executor. Fails only while the store is
+// unavailable.
+pub fn commit_inclusion(
+    store: &Mutex<Box<dyn Store>>,
+    branches: &[String],
+    commits: &[String],
+) -> StoreResult<Vec<bool>> {
+    match current_config().reachability {
+        Reachability::Bitmap => {
+            let included = store.lock().unwrap().branch_inclusion(branches, commits);
+            match included {
+                Ok(included) => Ok(included),
+                Err(e @ StoreError::Unavailable(_)) => Err(e),
+                Err(e) => {
+                    warn!(error = %e, "Failed to check reachability in the store");
+                    Ok(vec![false; branches.len()])
+                }
+            }
+        }
+        Reachability::Graph => match Repository::open(REPO_PATH)
+            .and_then(|repo| graph_inclusion(&repo, store, branches, commits))
+        {
+            Ok(included) => Ok(included),
+            Err(e) => {
+                warn!(error = %e, "Failed to check reachability in git");
+                Ok(vec![false; branches.len()])
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{merges::tests::Fixture, store::StoreConfig};
+
+    #[test]
+    fn negative_answers_are_evicted_when_the_tip_moves() {
+        let mut fixture = Fixture::new();
+        let c0 = fixture.commit(&[], "Initial commit");
+        let f1 = fixture.commit(&[c0], "hello: init at 1.0");
+        let m1 = fixture.commit(&[c0, f1], "Merge pull request #1 from alice/hello");
+        let store = Mutex::new(StoreConfig::memory().open().unwrap());
+        let branches = ["master".to_string()];
+        let (c0_hex, f1_hex) = (c0.to_string(), f1.to_string());
+        let included = |commit: &str| {
+            graph_inclusion(&fixture.repo, &store, &branches, &[commit.to_string()]).unwrap()
+        };
+        store
+            .lock()
+            .unwrap()
+            .set_branch_tip("master", &c0_hex)
+            .unwrap();
+        assert_eq!(included(&c0_hex), [true]);
+        assert_eq!(included(&f1_hex), [false]);
+        assert_eq!(
+            store.lock().unwrap().reachable("master", &f1_hex).unwrap(),
+            Some(c0_hex.clone())
+        );
+
+        let mut locked = store.lock().unwrap();
+        locked.set_branch_tip("master", &m1.to_string()).unwrap();
+        locked.forget_unreachable("master").unwrap();
+        assert_eq!(locked.reachable("master", &f1_hex).unwrap(), None);
+        assert_eq!(
+            locked.reachable("master", &c0_hex).unwrap(),
+            Some("1".into())
+        );
+        drop(locked);
+        assert_eq!(included(&f1_hex), [true]);
+    }
+}
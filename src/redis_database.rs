@@ -1,112 +1,820 @@
-use git2::{Error, Repository};
-use redis::{Commands, Connection, RedisError, RedisResult};
+use std::{
+    cmp::min,
+    env, io,
+    time::{Duration, Instant},
+};
+
+use git2::Oid;
+use redis::{
+    cluster::{cluster_pipe, ClusterClient, ClusterConnection},
+    sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType},
+    Cmd, Commands, Connection, ConnectionAddr, ConnectionLike, ErrorKind, IntoConnectionInfo,
+    RedisError, RedisResult, TlsMode, Value,
+};
+use tracing::{info, warn};
 
 use crate::{
-    config::{IndexState, CACHED_BRANCHES},
-    switch_branch, update_git_repo,
+    config::IndexState,
+    eta::{Advance, HISTORY_MAX_ENTRIES, LAG_MAX_SAMPLES},
+    feed::{FeedEntry, FEED_MAX_ENTRIES},
+    store::{Store, StoreResult},
+    watch::Watch,
 };
 
-pub fn index_redis(repo: &Repository, con: &mut Connection) {
-    println!("Indexing commits into redis database. It may take a while...");
-    let _ = cache_commit_to_redis(&repo, con);
-    let _ = set_index_state(con, IndexState::Ready);
-    println!("Successfully indexing commits.");
+// How to reach Redis. With Sentinel and Cluster, REDIS_URL lists the sentinels
+// or the seed nodes separated by commas.
+#[derive(Clone, Debug)]
+pub enum RedisConfig {
+    Standalone(String),
+    Sentinel { nodes: Vec<String>, master: String },
+    Cluster(Vec<String>),
 }
 
-pub fn open_redis_connection(url: String) -> Result<RedisResult<Connection>, RedisError> {
-    let client = redis::Client::open(url)?;
+impl RedisConfig {
+    pub fn from_env(url: String) -> Result<RedisConfig, String> {
+        let nodes: Vec<String> = url
+            .split(',')
+            .map(str::trim)
+            .filter(|node| !node.is_empty())
+            .map(String::from)
+            .collect();
+        match env::var("REDIS_MODE").as_deref() {
+            Ok("standalone") | Err(_) => Ok(RedisConfig::Standalone(url)),
+            Ok("sentinel") => Ok(RedisConfig::Sentinel {
+                nodes,
+                master: env::var("REDIS_SENTINEL_MASTER").unwrap_or("mymaster".to_string()),
+            }),
+            Ok("cluster") => Ok(RedisConfig::Cluster(nodes)),
+            Ok(mode) => Err(format!(
+                "Invalid REDIS_MODE {}, expected standalone, sentinel or cluster",
+                mode
+            )),
+        }
+    }
+}
 
-    return Ok(client.get_connection());
+enum RedisConnection {
+    Single(Connection),
+    Cluster(ClusterConnection),
 }
 
-pub fn set_index_state(con: &mut Connection, state: IndexState) -> Result<(), RedisError> {
-    con.set(
-        "STATE",
-        match state {
-            IndexState::Starting => "STARTING",
-            IndexState::CloningGitRepo => "CLONING_GIT_REPO",
-            IndexState::IndexingCommit => "INDEXING_COMMIT",
-            IndexState::Ready => "READY",
-        },
-    )
+impl RedisConnection {
+    // Cluster connections refuse plain pipelines, so the commands go through a
+    // cluster pipeline, which groups them by node. Replies are discarded.
+    fn run(&mut self, pipe: &redis::Pipeline) -> RedisResult<()> {
+        match self {
+            RedisConnection::Single(con) => pipe.query(con),
+            RedisConnection::Cluster(con) => {
+                let mut cluster_pipe = cluster_pipe();
+                for cmd in pipe.cmd_iter() {
+                    cluster_pipe.add_command(cmd.clone()).ignore();
+                }
+                cluster_pipe.query(con)
+            }
+        }
+    }
 }
 
-pub fn write_cache_to_redis(
-    branch: &str,
-    repo: &Repository,
-    con: &mut Connection,
-    is_delta_update: bool,
-) -> Result<(), Error> {
-    let mut revwalk = repo.revwalk()?;
-    let mut latest_sha1: String = "".to_string();
-    revwalk.set_sorting(git2::Sort::REVERSE)?;
-    revwalk.push_head()?;
-
-    if is_delta_update {
-        for commit_id in revwalk {
-            let commit_id = commit_id?;
-            let _: () = con
-                .sadd(
-                    format!("{}_DELTA", branch.to_uppercase()),
-                    commit_id.to_string(),
-                )
-                .unwrap();
-            latest_sha1 = commit_id.to_string();
-        }
-        let _: () = con
-            .rename(
-                format!("{}_DELTA", branch.to_uppercase()),
-                branch.to_uppercase(),
-            )
-            .unwrap();
-    } else {
-        let _: () = con.del(branch.to_uppercase()).unwrap();
-        for commit_id in revwalk {
-            let commit_id = commit_id?;
-            let _: () = con
-                .sadd(branch.to_uppercase(), commit_id.to_string())
-                .unwrap();
-            latest_sha1 = commit_id.to_string();
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_command(cmd),
+            RedisConnection::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        match self {
+            RedisConnection::Single(con) => con.req_command(cmd),
+            RedisConnection::Cluster(con) => con.req_command(cmd),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(con) => con.get_db(),
+            RedisConnection::Cluster(con) => con.get_db(),
+        }
+    }
+
+    fn supports_pipelining(&self) -> bool {
+        match self {
+            RedisConnection::Single(con) => con.supports_pipelining(),
+            RedisConnection::Cluster(con) => con.supports_pipelining(),
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match self {
+            RedisConnection::Single(con) => con.check_connection(),
+            RedisConnection::Cluster(con) => con.check_connection(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            RedisConnection::Single(con) => con.is_open(),
+            RedisConnection::Cluster(con) => con.is_open(),
+        }
+    }
+}
+
+// Bounds how long a request waits on an unreachable standalone server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Errors after which the connection is useless. A former master answering
+// READONLY after a Sentinel failover counts too: reconnecting asks for the new one.
+pub fn is_connection_error(e: &RedisError) -> bool {
+    e.is_io_error()
+        || e.is_connection_dropped()
+        || e.is_connection_refusal()
+        || e.is_timeout()
+        || e.kind() == ErrorKind::ReadOnly
+}
+
+fn connect(config: &RedisConfig) -> RedisResult<RedisConnection> {
+    Ok(match config {
+        RedisConfig::Standalone(url) => RedisConnection::Single(
+            redis::Client::open(url.as_str())?.get_connection_with_timeout(CONNECT_TIMEOUT)?,
+        ),
+        RedisConfig::Sentinel { nodes, master } => {
+            // The master is reached with the credentials and TLS of the first sentinel URL.
+            let first = nodes.first().map(String::as_str).unwrap_or_default();
+            let info = first.into_connection_info()?;
+            let tls_mode = match info.addr {
+                ConnectionAddr::TcpTls { insecure: true, .. } => Some(TlsMode::Insecure),
+                ConnectionAddr::TcpTls { .. } => Some(TlsMode::Secure),
+                _ => None,
+            };
+            let node = SentinelNodeConnectionInfo {
+                tls_mode,
+                redis_connection_info: Some(info.redis),
+            };
+            let mut client = SentinelClient::build(
+                nodes.clone(),
+                master.clone(),
+                Some(node),
+                SentinelServerType::Master,
+            )?;
+            RedisConnection::Single(client.get_connection()?)
+        }
+        RedisConfig::Cluster(nodes) => {
+            RedisConnection::Cluster(ClusterClient::new(nodes.clone())?.get_connection()?)
+        }
+    })
+}
+
+// Drops the connection on the first connection error and opens a new one on
+// the next command. Failed attempts back off exponentially, so an outage costs
+// one connection attempt per backoff period rather than one per request.
+struct ReconnectingConnection {
+    config: RedisConfig,
+    con: Option<RedisConnection>,
+    failures: u32,
+    retry_at: Instant,
+}
+
+impl ReconnectingConnection {
+    fn connection(&mut self) -> RedisResult<&mut RedisConnection> {
+        if self.con.is_none() {
+            if Instant::now() < self.retry_at {
+                return Err(RedisError::from(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Redis is unreachable, waiting before reconnecting",
+                )));
+            }
+            match connect(&self.config) {
+                Ok(con) => {
+                    if self.failures > 0 {
+                        info!(attempts = self.failures + 1, "Reconnected to Redis.");
+                    }
+                    self.failures = 0;
+                    self.con = Some(con);
+                }
+                Err(e) => {
+                    let backoff = min(MIN_BACKOFF * 2u32.pow(min(self.failures, 6)), MAX_BACKOFF);
+                    self.failures += 1;
+                    self.retry_at = Instant::now() + backoff;
+                    warn!(error = %e, ?backoff, "Failed to connect to Redis");
+                    return Err(e);
+                }
+            }
+        }
+        Ok(self.con.as_mut().unwrap())
+    }
+
+    fn track<T>(&mut self, result: RedisResult<T>) -> RedisResult<T> {
+        if let Err(e) = &result {
+            if is_connection_error(e) && self.con.take().is_some() {
+                warn!(error = %e, "Lost connection to Redis");
+            }
         }
+        result
+    }
+
+    fn run(&mut self, pipe: &redis::Pipeline) -> RedisResult<()> {
+        let result = self.connection()?.run(pipe);
+        self.track(result)
     }
+}
 
-    let _: () = con
-        .set(
-            format!("LAST_{}_COMMIT", branch.to_uppercase()),
-            latest_sha1,
-        )
-        .unwrap();
+impl ConnectionLike for ReconnectingConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let result = self.connection()?.req_packed_command(cmd);
+        self.track(result)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let result = self.connection()?.req_packed_commands(cmd, offset, count);
+        self.track(result)
+    }
+
+    fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        let result = self.connection()?.req_command(cmd);
+        self.track(result)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.con.as_ref().map_or(0, |con| con.get_db())
+    }
+
+    fn supports_pipelining(&self) -> bool {
+        !matches!(self.config, RedisConfig::Cluster(_))
+    }
 
-    Ok(())
+    fn check_connection(&mut self) -> bool {
+        self.connection()
+            .map(|con| con.check_connection())
+            .unwrap_or(false)
+    }
+
+    fn is_open(&self) -> bool {
+        self.con.as_ref().is_some_and(|con| con.is_open())
+    }
 }
 
-pub fn cache_commit_to_redis(repo: &Repository, con: &mut Connection) -> Result<(), RedisError> {
-    for branch in &CACHED_BRANCHES {
-        let remote_branch_name = match branch {
-            &"master" => "master",
-            _ => &format!("origin/{}", branch),
+pub struct RedisStore {
+    con: ReconnectingConnection,
+}
+
+impl RedisStore {
+    // Redis being down at startup is not fatal, the first command connects.
+    pub fn open(config: &RedisConfig) -> StoreResult<RedisStore> {
+        let mut con = ReconnectingConnection {
+            config: config.clone(),
+            con: None,
+            failures: 0,
+            retry_at: Instant::now(),
         };
-        match switch_branch(&remote_branch_name, repo) {
-            Ok(_) => {
-                println!("Indexing {} branch. Please wait.", branch);
-                let maybe_empty_string: String = con
-                    .get(format!("LAST_{}_COMMIT", branch.to_uppercase()))
-                    .unwrap_or("".to_string());
-
-                if maybe_empty_string.len() > 0 {
-                    let _ = set_index_state(con, IndexState::Ready); // Assume previous cache for all branches is available
-                    println!("Branch {} is already indexed. Do A/B updates.", branch);
-                    update_git_repo(repo, branch);
-                    let _ = write_cache_to_redis(branch, repo, con, true);
-                } else {
-                    let _ = set_index_state(con, IndexState::IndexingCommit);
-                    println!("Branch {} is not indexed. Do full updates.", branch);
-                    update_git_repo(repo, branch);
-                    let _ = write_cache_to_redis(branch, repo, con, true);
+        match con.connection() {
+            Err(e) if !is_connection_error(&e) => return Err(e.into()),
+            _ => {}
+        }
+        Ok(RedisStore { con })
+    }
+
+    // In a cluster every key of a branch carries the branch as hash tag, so they
+    // share a slot and can be renamed into each other or deleted together.
+    fn branch(&self, branch: &str) -> String {
+        match self.con.config {
+            RedisConfig::Cluster(_) => format!("{{{}}}", branch.to_uppercase()),
+            _ => branch.to_uppercase(),
+        }
+    }
+
+    fn membership_key(&self, branch: &str, delta: bool) -> String {
+        match delta {
+            true => format!("{}_DELTA", self.branch(branch)),
+            false => self.branch(branch),
+        }
+    }
+
+    // Commits are numbered densely in the COMMIT_IDS hash (raw 20 byte object id ->
+    // number), and each branch is a bitmap with the bit of every commit reachable
+    // from it set. A bit per commit and branch plus one hash entry per commit is an
+    // order of magnitude smaller than a set of hex strings per branch.
+    fn commit_ids(&mut self, commits: &[Oid]) -> StoreResult<Vec<Option<u64>>> {
+        if commits.is_empty() {
+            return Ok(vec![]);
+        }
+        let fields: Vec<&[u8]> = commits.iter().map(|commit| commit.as_bytes()).collect();
+        Ok(redis::cmd("HMGET")
+            .arg("COMMIT_IDS")
+            .arg(fields)
+            .query(&mut self.con)?)
+    }
+
+    // Numbers the commits seen for the first time. Only the indexer thread writes.
+    fn assign_commit_ids(&mut self, commits: &[Oid]) -> StoreResult<Vec<u64>> {
+        let mut ids = self.commit_ids(commits)?;
+        let missing = ids.iter().filter(|id| id.is_none()).count() as u64;
+        if missing == 0 {
+            return Ok(ids.into_iter().flatten().collect());
+        }
+        let last: u64 = self.con.incr("NEXT_COMMIT_ID", missing)?;
+        let mut next = last - missing;
+        let mut assigned: Vec<(&[u8], u64)> = vec![];
+        for (commit, id) in commits.iter().zip(ids.iter_mut()) {
+            if id.is_none() {
+                *id = Some(next);
+                assigned.push((commit.as_bytes(), next));
+                next += 1;
+            }
+        }
+        let _: () = self.con.hset_multiple("COMMIT_IDS", &assigned)?;
+        Ok(ids.into_iter().flatten().collect())
+    }
+}
+
+impl Store for RedisStore {
+    // A connection that was open before Redis went away only notices on its
+    // next command, so one is sent.
+    fn check(&mut self) -> StoreResult<()> {
+        let _: String = redis::cmd("PING").query(&mut self.con)?;
+        Ok(())
+    }
+
+    // Branches used to be sets of hex strings. Drop those and their tip so the next
+    // pass indexes the branch again as a bitmap. The merge index used to live
+    // under the `PR_` prefix; without it the next pass scans history for it again.
+    fn prepare(&mut self, branches: &[String]) -> StoreResult<()> {
+        for legacy in ["PR_MERGES", "PR_MERGES_SCANNED"] {
+            let dropped: usize = self.con.del(legacy)?;
+            if dropped > 0 {
+                info!(key = legacy, "Dropped legacy merge index key.");
+            }
+        }
+        for branch in branches {
+            // Before hash tags, cluster keys were named like standalone ones.
+            let mut names = vec![self.branch(branch)];
+            if !names.contains(&branch.to_uppercase()) {
+                names.push(branch.to_uppercase());
+            }
+            for name in names {
+                let kind: String = redis::cmd("TYPE").arg(&name).query(&mut self.con)?;
+                if kind == "set" {
+                    info!(
+                        branch,
+                        key = name,
+                        "Dropping legacy commit set. The branch will be indexed again."
+                    );
+                    // One at a time, untagged keys may live in different cluster slots.
+                    let _: () = self.con.del(format!("LAST_{}_COMMIT", name))?;
+                    let _: () = self.con.del(name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn index_state(&mut self) -> StoreResult<String> {
+        let state: Option<String> = self.con.get("STATE")?;
+        Ok(state.unwrap_or_default())
+    }
+
+    fn set_index_state(&mut self, state: IndexState) -> StoreResult<()> {
+        Ok(self.con.set("STATE", state.as_str())?)
+    }
+
+    fn branch_tip(&mut self, branch: &str) -> StoreResult<Option<String>> {
+        Ok(self
+            .con
+            .get(format!("LAST_{}_COMMIT", self.branch(branch)))?)
+    }
+
+    fn set_branch_tip(&mut self, branch: &str, tip: &str) -> StoreResult<()> {
+        Ok(self
+            .con
+            .set(format!("LAST_{}_COMMIT", self.branch(branch)), tip)?)
+    }
+
+    fn reset_branch(&mut self, branch: &str) -> StoreResult<()> {
+        Ok(self.con.del(&[
+            format!("LAST_{}_COMMIT", self.branch(branch)),
+            format!("{}_DELTA", self.branch(branch)),
+            format!("{}_FIRST_PARENTS", self.branch(branch)),
+            format!("{}_REACHABLE", self.branch(branch)),
+            format!("{}_UNREACHABLE", self.branch(branch)),
+        ])?)
+    }
+
+    // Bits are set a batch at a time, a round trip per commit is what makes indexing slow.
+    fn add_branch_commits(
+        &mut self,
+        branch: &str,
+        delta: bool,
+        commits: &[Oid],
+    ) -> StoreResult<()> {
+        if commits.is_empty() {
+            return Ok(());
+        }
+        let key = self.membership_key(branch, delta);
+        let mut pipe = redis::pipe();
+        for id in self.assign_commit_ids(commits)? {
+            pipe.setbit(&key, id as usize, true).ignore();
+        }
+        Ok(self.con.run(&pipe)?)
+    }
+
+    fn finish_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()> {
+        if !delta {
+            return Ok(());
+        }
+        Ok(self.con.rename(
+            self.membership_key(branch, true),
+            self.membership_key(branch, false),
+        )?)
+    }
+
+    fn discard_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()> {
+        Ok(self.con.del(self.membership_key(branch, delta))?)
+    }
+
+    fn branch_inclusion(
+        &mut self,
+        branches: &[String],
+        commits: &[String],
+    ) -> StoreResult<Vec<bool>> {
+        let oids: Vec<Oid> = commits
+            .iter()
+            .filter_map(|commit| Oid::from_str(commit).ok())
+            .collect();
+        // A commit that was never indexed is in no branch.
+        let ids = self.commit_ids(&oids)?;
+        if commits.is_empty() || oids.len() < commits.len() || ids.iter().any(|id| id.is_none()) {
+            return Ok(vec![commits.is_empty(); branches.len()]);
+        }
+        let mut commit_exist_matrix: Vec<bool> = vec![];
+        for branch in branches {
+            let mut is_fully_included = true;
+            for id in ids.iter().flatten() {
+                let existence: bool = self.con.getbit(self.branch(branch), *id as usize)?;
+                if !existence {
+                    is_fully_included = false;
+                    break;
                 }
             }
-            Err(_) => panic!("Failed to checkout branch {}", branch),
+            commit_exist_matrix.push(is_fully_included);
+        }
+        Ok(commit_exist_matrix)
+    }
+
+    // Negative answers are kept apart, so that a moving tip drops them in one DEL.
+    fn reachable(&mut self, branch: &str, commit: &str) -> StoreResult<Option<String>> {
+        let reachable: Option<String> = self
+            .con
+            .hget(format!("{}_REACHABLE", self.branch(branch)), commit)?;
+        match reachable {
+            Some(answer) => Ok(Some(answer)),
+            None => Ok(self
+                .con
+                .hget(format!("{}_UNREACHABLE", self.branch(branch)), commit)?),
+        }
+    }
+
+    fn save_reachable(&mut self, branch: &str, commit: &str, answer: &str) -> StoreResult<()> {
+        let (key, other) = match answer {
+            "1" => ("REACHABLE", "UNREACHABLE"),
+            _ => ("UNREACHABLE", "REACHABLE"),
+        };
+        let mut pipe = redis::pipe();
+        pipe.hset(format!("{}_{}", self.branch(branch), key), commit, answer)
+            .ignore()
+            .hdel(format!("{}_{}", self.branch(branch), other), commit)
+            .ignore();
+        Ok(self.con.run(&pipe)?)
+    }
+
+    fn clear_reachable(&mut self, branch: &str) -> StoreResult<()> {
+        Ok(self.con.del(&[
+            format!("{}_REACHABLE", self.branch(branch)),
+            format!("{}_UNREACHABLE", self.branch(branch)),
+        ])?)
+    }
+
+    fn forget_unreachable(&mut self, branch: &str) -> StoreResult<()> {
+        Ok(self
+            .con
+            .del(format!("{}_UNREACHABLE", self.branch(branch)))?)
+    }
+
+    fn save_pr_merges(&mut self, merges: &[(u64, String)]) -> StoreResult<()> {
+        if merges.is_empty() {
+            return Ok(());
+        }
+        Ok(self.con.hset_multiple("MERGE_INDEX", merges)?)
+    }
+
+    fn pr_merge(&mut self, pr: u64) -> StoreResult<Option<String>> {
+        Ok(self.con.hget("MERGE_INDEX", pr)?)
+    }
+
+    fn merges_scanned(&mut self, branch: &str) -> StoreResult<bool> {
+        Ok(self.con.sismember("MERGE_INDEX_SCANNED", branch)?)
+    }
+
+    fn set_merges_scanned(&mut self, branch: &str) -> StoreResult<()> {
+        Ok(self.con.sadd("MERGE_INDEX_SCANNED", branch)?)
+    }
+
+    fn forget_pr_merge(&mut self, pr: u64) -> StoreResult<bool> {
+        let forgotten: usize = self.con.hdel("MERGE_INDEX", pr)?;
+        Ok(forgotten > 0)
+    }
+
+    fn feed(&mut self, branch: &str) -> StoreResult<Vec<FeedEntry>> {
+        let entries: Vec<String> =
+            self.con
+                .lrange(format!("FEED_{}", self.branch(branch)), 0, -1)?;
+        Ok(entries
+            .iter()
+            .filter_map(|entry| serde_json::from_str(entry).ok())
+            .collect())
+    }
+
+    fn push_feed_entries(&mut self, branch: &str, entries: &[FeedEntry]) -> StoreResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let key = format!("FEED_{}", self.branch(branch));
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .collect();
+        let mut pipe = redis::pipe();
+        pipe.lpush(&key, entries)
+            .ignore()
+            .ltrim(&key, 0, FEED_MAX_ENTRIES as isize - 1)
+            .ignore();
+        Ok(self.con.run(&pipe)?)
+    }
+
+    fn history(&mut self, branch: &str, count: usize) -> StoreResult<Vec<Advance>> {
+        let entries: Vec<String> = self.con.lrange(
+            format!("HISTORY_{}", self.branch(branch)),
+            0,
+            count as isize - 1,
+        )?;
+        Ok(entries
+            .iter()
+            .filter_map(|entry| serde_json::from_str(entry).ok())
+            .collect())
+    }
+
+    fn push_history(&mut self, branch: &str, advance: &Advance) -> StoreResult<()> {
+        let key = format!("HISTORY_{}", self.branch(branch));
+        let mut pipe = redis::pipe();
+        pipe.lpush(&key, serde_json::to_string(advance).unwrap())
+            .ignore()
+            .ltrim(&key, 0, HISTORY_MAX_ENTRIES as isize - 1)
+            .ignore();
+        Ok(self.con.run(&pipe)?)
+    }
+
+    // ARRIVALS_{BRANCH} scores each PR merge commit with the time it first became
+    // reachable from the branch.
+    fn arrival(&mut self, branch: &str, commit: &str) -> StoreResult<Option<i64>> {
+        Ok(self
+            .con
+            .zscore(format!("ARRIVALS_{}", self.branch(branch)), commit)?)
+    }
+
+    fn save_arrivals(
+        &mut self,
+        branch: &str,
+        arrivals: &[(String, i64)],
+        oldest: i64,
+    ) -> StoreResult<()> {
+        let key = format!("ARRIVALS_{}", self.branch(branch));
+        let mut pipe = redis::pipe();
+        for (commit, at) in arrivals {
+            pipe.cmd("ZADD")
+                .arg(&key)
+                .arg("NX")
+                .arg(at)
+                .arg(commit)
+                .ignore();
         }
+        pipe.zrembyscore(&key, "-inf", oldest).ignore();
+        Ok(self.con.run(&pipe)?)
+    }
+
+    fn lag_samples(&mut self, upstream: &str, branch: &str) -> StoreResult<Vec<i64>> {
+        Ok(self.con.lrange(
+            format!("LAGS_{}_{}", self.branch(upstream), self.branch(branch)),
+            0,
+            -1,
+        )?)
+    }
+
+    fn push_lag_samples(&mut self, upstream: &str, branch: &str, lags: &[i64]) -> StoreResult<()> {
+        if lags.is_empty() {
+            return Ok(());
+        }
+        let key = format!("LAGS_{}_{}", self.branch(upstream), self.branch(branch));
+        let mut pipe = redis::pipe();
+        pipe.lpush(&key, lags)
+            .ignore()
+            .ltrim(&key, 0, LAG_MAX_SAMPLES as isize - 1)
+            .ignore();
+        Ok(self.con.run(&pipe)?)
+    }
+
+    // {BRANCH}_FIRST_PARENTS scores each commit with its position.
+    fn first_parent_top(&mut self, branch: &str) -> StoreResult<Option<(String, usize)>> {
+        let top: Vec<(String, usize)> = self.con.zrevrange_withscores(
+            format!("{}_FIRST_PARENTS", self.branch(branch)),
+            0,
+            0,
+        )?;
+        Ok(top.into_iter().next())
+    }
+
+    fn add_first_parents(
+        &mut self,
+        branch: &str,
+        start: usize,
+        commits: &[String],
+    ) -> StoreResult<()> {
+        if commits.is_empty() {
+            return Ok(());
+        }
+        let items: Vec<(usize, &String)> = commits
+            .iter()
+            .enumerate()
+            .map(|(offset, commit_id)| (start + offset, commit_id))
+            .collect();
+        Ok(self
+            .con
+            .zadd_multiple(format!("{}_FIRST_PARENTS", self.branch(branch)), &items)?)
+    }
+
+    fn clear_first_parents(&mut self, branch: &str) -> StoreResult<()> {
+        Ok(self
+            .con
+            .del(format!("{}_FIRST_PARENTS", self.branch(branch)))?)
+    }
+
+    fn first_parent_count(&mut self, branch: &str) -> StoreResult<usize> {
+        Ok(self
+            .con
+            .zcard(format!("{}_FIRST_PARENTS", self.branch(branch)))?)
+    }
+
+    fn first_parent_at(&mut self, branch: &str, position: usize) -> StoreResult<Option<String>> {
+        let commits: Vec<String> = self.con.zrangebyscore(
+            format!("{}_FIRST_PARENTS", self.branch(branch)),
+            position,
+            position,
+        )?;
+        Ok(commits.into_iter().next())
+    }
+
+    // Watches are kept as JSON in the WATCHES hash, keyed by their id.
+    fn save_watch(&mut self, watch: &Watch) -> StoreResult<()> {
+        Ok(self
+            .con
+            .hset("WATCHES", &watch.id, serde_json::to_string(watch).unwrap())?)
+    }
+
+    fn watch(&mut self, id: &str) -> StoreResult<Option<Watch>> {
+        let watch: Option<String> = self.con.hget("WATCHES", id)?;
+        Ok(watch.and_then(|watch| serde_json::from_str(&watch).ok()))
+    }
+
+    fn watches(&mut self) -> StoreResult<Vec<Watch>> {
+        let watches: Vec<String> = self.con.hvals("WATCHES")?;
+        Ok(watches
+            .iter()
+            .filter_map(|watch| serde_json::from_str(watch).ok())
+            .collect())
+    }
+
+    fn delete_watch(&mut self, id: &str) -> StoreResult<bool> {
+        let removed: usize = self.con.hdel("WATCHES", id)?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use git2::ObjectType;
+
+    use super::*;
+    use crate::store::tests::check_conformance;
+
+    // Redis tests run against TEST_REDIS_URL and flush it, so it must point at a
+    // scratch database. They pass without doing anything when it is unset.
+    static REDIS: Mutex<()> = Mutex::new(());
+
+    fn with_redis(test: impl FnOnce(&mut RedisStore)) {
+        let Ok(url) = env::var("TEST_REDIS_URL") else {
+            return;
+        };
+        let _guard = REDIS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut store = RedisStore::open(&RedisConfig::Standalone(url)).unwrap();
+        let _: () = redis::cmd("FLUSHDB").query(&mut store.con).unwrap();
+        test(&mut store);
+    }
+
+    fn commit(name: &str) -> Oid {
+        Oid::hash_object(ObjectType::Commit, name.as_bytes()).unwrap()
+    }
+
+    fn branches() -> Vec<String> {
+        vec!["master".to_string(), "nixos-unstable".to_string()]
+    }
+
+    #[test]
+    fn redis_store_conforms() {
+        with_redis(|store| {
+            let config = store.con.config.clone();
+            check_conformance(|| Box::new(RedisStore::open(&config).unwrap()));
+        });
+    }
+
+    #[test]
+    fn commits_are_numbered_densely() {
+        with_redis(|store| {
+            let (c0, c1, c2) = (commit("c0"), commit("c1"), commit("c2"));
+            assert_eq!(store.assign_commit_ids(&[c0, c1]).unwrap(), [0, 1]);
+            // Known commits keep their number, new ones take the next.
+            assert_eq!(store.assign_commit_ids(&[c1, c2, c0]).unwrap(), [1, 2, 0]);
+            assert_eq!(
+                store.commit_ids(&[c2, commit("c3")]).unwrap(),
+                [Some(2), None]
+            );
+            assert_eq!(store.commit_ids(&[]).unwrap(), []);
+
+            store.add_branch_commits("master", true, &[c2, c0]).unwrap();
+            let bit = |store: &mut RedisStore, key: &str, id: usize| -> bool {
+                store.con.getbit(key, id).unwrap()
+            };
+            assert!(bit(store, "MASTER_DELTA", 0));
+            assert!(!bit(store, "MASTER_DELTA", 1));
+            assert!(bit(store, "MASTER_DELTA", 2));
+            // One byte covers the three commits.
+            let bitmap: Vec<u8> = store.con.get("MASTER_DELTA").unwrap();
+            assert_eq!(bitmap, [0b1010_0000]);
+
+            store.finish_branch_commits("master", true).unwrap();
+            let delta: bool = store.con.exists("MASTER_DELTA").unwrap();
+            assert!(!delta);
+            assert!(bit(store, "MASTER", 0));
+            assert!(bit(store, "MASTER", 2));
+        });
+    }
+
+    #[test]
+    fn legacy_keys_are_dropped_on_prepare() {
+        with_redis(|store| {
+            let c0 = commit("c0").to_string();
+            let _: () = store.con.sadd("MASTER", &c0).unwrap();
+            let _: () = store.con.set("LAST_MASTER_COMMIT", &c0).unwrap();
+            let _: () = store.con.sadd("NIXOS-UNSTABLE", &c0).unwrap();
+            let _: () = store.con.hset("PR_MERGES", 1, &c0).unwrap();
+            let _: () = store.con.sadd("PR_MERGES_SCANNED", "master").unwrap();
+            let _: () = store.con.set("LAST_STAGING_COMMIT", &c0).unwrap();
+            store
+                .add_branch_commits("staging", false, &[commit("c0")])
+                .unwrap();
+
+            store.prepare(&branches()).unwrap();
+            for key in ["MASTER", "LAST_MASTER_COMMIT", "NIXOS-UNSTABLE"] {
+                let exists: bool = store.con.exists(key).unwrap();
+                assert!(!exists, "{} was kept", key);
+            }
+            for key in ["PR_MERGES", "PR_MERGES_SCANNED"] {
+                let exists: bool = store.con.exists(key).unwrap();
+                assert!(!exists, "{} was kept", key);
+            }
+            // Untracked branches and bitmaps are left alone.
+            assert_eq!(store.branch_tip("staging").unwrap(), Some(c0.clone()));
+            let inclusion = store
+                .branch_inclusion(&["staging".to_string()], &[c0])
+                .unwrap();
+            assert_eq!(inclusion, [true]);
+        });
     }
-    Ok(())
 }
@@ -0,0 +1,172 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use git2::Repository;
+use tracing::{info, warn};
+
+use crate::{
+    config::{current_config, set_config, tracked_branches, Config},
+    indexer::{index_branches, reset_branches},
+    maintenance::maintain_if_due,
+    shutdown::shutdown_requested,
+    store::Store,
+};
+
+// Work that other threads (signal handler, web server) hand to the indexer.
+// Everything touching the git repo runs on the indexer thread, one job at a time.
+pub enum IndexerCommand {
+    IndexBranches(Vec<String>),
+    RebuildBranches(Vec<String>),
+    Shutdown,
+}
+
+// Published after every indexing pass so that other parts of the tracker can
+// react to branches moving forward.
+#[derive(Clone, Debug)]
+pub enum IndexEvent {
+    PassFinished { branches: Vec<String> },
+}
+
+static LISTENERS: Mutex<Vec<Sender<IndexEvent>>> = Mutex::new(vec![]);
+
+pub fn subscribe_index_events() -> Receiver<IndexEvent> {
+    let (tx, rx) = channel();
+    LISTENERS.lock().unwrap().push(tx);
+    rx
+}
+
+fn publish_index_event(event: IndexEvent) {
+    // Listeners that went away are dropped on the next publish.
+    LISTENERS
+        .lock()
+        .unwrap()
+        .retain(|listener| listener.send(event.clone()).is_ok());
+}
+
+// Waits out a store outage instead of failing every branch of the pass.
+// Returns false if a shutdown was requested meanwhile.
+fn wait_for_store(store: &mut dyn Store) -> bool {
+    let Err(e) = store.check() else {
+        return true;
+    };
+    warn!(error = %e, "Store is unavailable. Waiting before indexing.");
+    while store.check().is_err() {
+        if shutdown_requested() {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    info!("Store is available again. Indexing.");
+    true
+}
+
+fn run_pass(repo: &Repository, store: &mut dyn Store, branches: Vec<String>) -> Result<(), String> {
+    if !wait_for_store(store) {
+        return Ok(());
+    }
+    index_branches(repo, store, &branches)?;
+    if !shutdown_requested() {
+        publish_index_event(IndexEvent::PassFinished { branches });
+    }
+    Ok(())
+}
+
+// While paused, periodic refreshes are skipped. Explicit commands still run.
+static SCHEDULER_PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_scheduler_paused(paused: bool) {
+    SCHEDULER_PAUSED.store(paused, Ordering::SeqCst);
+    info!(paused, "Scheduler state changed.");
+}
+
+pub fn scheduler_paused() -> bool {
+    SCHEDULER_PAUSED.load(Ordering::SeqCst)
+}
+
+// Indexes every tracked branch once, then keeps them fresh until shutdown.
+// Returns early with the problem found when the git repo breaks, so that it
+// can be cloned again.
+pub fn run_scheduler(
+    repo: &Repository,
+    store: &mut dyn Store,
+    commands: &Receiver<IndexerCommand>,
+) -> Result<(), String> {
+    run_pass(repo, store, tracked_branches())?;
+    maintain_if_due(repo);
+    while !shutdown_requested() {
+        let interval = Duration::from_secs(current_config().refresh_interval);
+        match commands.recv_timeout(interval) {
+            Ok(IndexerCommand::IndexBranches(branches)) if branches.is_empty() => {}
+            Ok(IndexerCommand::IndexBranches(branches)) => run_pass(repo, store, branches)?,
+            Ok(IndexerCommand::RebuildBranches(branches)) => {
+                let _ = reset_branches(store, &branches);
+                run_pass(repo, store, branches)?;
+            }
+            Ok(IndexerCommand::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) if scheduler_paused() => {
+                info!("Scheduler is paused. Skipping refresh.");
+            }
+            Err(RecvTimeoutError::Timeout) => run_pass(repo, store, tracked_branches())?,
+        }
+        if !shutdown_requested() {
+            maintain_if_due(repo);
+        }
+    }
+    Ok(())
+}
+
+// Re-read the configuration and apply it: swap the GitHub token used by the
+// web server and ask the indexer to pick up newly tracked branches. Removed
+// branches are simply no longer refreshed; their stored data is left alone.
+pub fn reload_config(
+    github_token: &Mutex<String>,
+    indexer: &Sender<IndexerCommand>,
+) -> Result<Config, String> {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            warn!(error = %e, "Failed to reload configuration. Keeping the old one.");
+            return Err(e);
+        }
+    };
+    let previous = current_config();
+    let added: Vec<String> = config
+        .branches
+        .iter()
+        .filter(|branch| !previous.branches.contains(branch))
+        .cloned()
+        .collect();
+    let removed: Vec<String> = previous
+        .branches
+        .iter()
+        .filter(|branch| !config.branches.contains(branch))
+        .cloned()
+        .collect();
+
+    *github_token.lock().unwrap() = config.github_token.clone();
+    set_config(config.clone());
+    info!(
+        ?added,
+        ?removed,
+        refresh_interval = config.refresh_interval,
+        reachability = ?config.reachability,
+        token_changed = previous.github_token != config.github_token,
+        "Reloaded configuration."
+    );
+
+    // Switching reachability engines needs a pass over every branch, to build
+    // or drop the bitmaps.
+    let reindex = match previous.reachability == config.reachability {
+        true => added,
+        false => config.branches.clone(),
+    };
+    let _ = indexer.send(IndexerCommand::IndexBranches(reindex));
+    Ok(config)
+}
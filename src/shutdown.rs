@@ -0,0 +1,13 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set once SIGINT/SIGTERM is received. Long running work (fetching, indexing)
+// polls this flag at safe points and bails out so the process can exit cleanly.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
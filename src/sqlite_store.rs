@@ -0,0 +1,534 @@
+use std::{collections::HashMap, time::Duration};
+
+use git2::Oid;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    config::IndexState,
+    eta::{Advance, HISTORY_MAX_ENTRIES, LAG_MAX_SAMPLES},
+    feed::{FeedEntry, FEED_MAX_ENTRIES},
+    store::{Store, StoreResult},
+    watch::Watch,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS branch_tips (branch TEXT PRIMARY KEY, tip TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS commits (id INTEGER PRIMARY KEY, oid BLOB NOT NULL UNIQUE);
+CREATE TABLE IF NOT EXISTS branch_commits (branch TEXT PRIMARY KEY, bits BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS reachable (
+    branch TEXT NOT NULL, commit_id TEXT NOT NULL, answer TEXT NOT NULL,
+    PRIMARY KEY (branch, commit_id)
+);
+CREATE TABLE IF NOT EXISTS pr_merges (pr INTEGER PRIMARY KEY, commit_id TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS pr_merges_scanned (branch TEXT PRIMARY KEY);
+CREATE TABLE IF NOT EXISTS lists (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, value TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS lists_name ON lists (name, seq);
+CREATE TABLE IF NOT EXISTS arrivals (
+    branch TEXT NOT NULL, commit_id TEXT NOT NULL, at INTEGER NOT NULL,
+    PRIMARY KEY (branch, commit_id)
+);
+CREATE TABLE IF NOT EXISTS first_parents (
+    branch TEXT NOT NULL, position INTEGER NOT NULL, commit_id TEXT NOT NULL,
+    PRIMARY KEY (branch, position)
+);
+CREATE TABLE IF NOT EXISTS watches (id TEXT PRIMARY KEY, watch TEXT NOT NULL);
+";
+
+// An SQLite file next to the binary, for deployments without Redis. Branch
+// membership is a bitmap blob per branch, like the Redis bitmaps; the one being
+// written is kept in memory until it is finished.
+pub struct SqliteStore {
+    con: Connection,
+    building: HashMap<(String, bool), Vec<u8>>,
+}
+
+// Bit `id` is the most significant bit first, as in Redis bitmaps.
+fn set_bit(bits: &mut Vec<u8>, id: u64) {
+    let byte = (id / 8) as usize;
+    if bits.len() <= byte {
+        bits.resize(byte + 1, 0);
+    }
+    bits[byte] |= 0x80 >> (id % 8);
+}
+
+fn list_name(kind: &str, parts: &[&str]) -> String {
+    format!("{}_{}", kind, parts.join("_"))
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> StoreResult<SqliteStore> {
+        let con = Connection::open(path)?;
+        // The indexer, the notifier and the web server each have a connection.
+        con.busy_timeout(Duration::from_secs(30))?;
+        con.pragma_update(None, "journal_mode", "WAL")?;
+        con.pragma_update(None, "synchronous", "NORMAL")?;
+        con.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            con,
+            building: HashMap::new(),
+        })
+    }
+
+    fn commit_ids(&mut self, commits: &[Oid]) -> StoreResult<Vec<Option<u64>>> {
+        let mut statement = self
+            .con
+            .prepare_cached("SELECT id FROM commits WHERE oid = ?1")?;
+        let mut ids = vec![];
+        for commit in commits {
+            ids.push(
+                statement
+                    .query_row([commit.as_bytes()], |row| row.get(0))
+                    .optional()?,
+            );
+        }
+        Ok(ids)
+    }
+
+    fn assign_commit_ids(&mut self, commits: &[Oid]) -> StoreResult<Vec<u64>> {
+        let transaction = self.con.transaction()?;
+        let mut ids = vec![];
+        {
+            let mut insert =
+                transaction.prepare_cached("INSERT OR IGNORE INTO commits (oid) VALUES (?1)")?;
+            let mut select = transaction.prepare_cached("SELECT id FROM commits WHERE oid = ?1")?;
+            for commit in commits {
+                insert.execute([commit.as_bytes()])?;
+                ids.push(select.query_row([commit.as_bytes()], |row| row.get(0))?);
+            }
+        }
+        transaction.commit()?;
+        Ok(ids)
+    }
+
+    fn push_list(&mut self, name: &str, values: &[String], max: usize) -> StoreResult<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let transaction = self.con.transaction()?;
+        {
+            let mut insert =
+                transaction.prepare_cached("INSERT INTO lists (name, value) VALUES (?1, ?2)")?;
+            for value in values {
+                insert.execute(params![name, value])?;
+            }
+        }
+        transaction.execute(
+            "DELETE FROM lists WHERE name = ?1 AND seq NOT IN
+             (SELECT seq FROM lists WHERE name = ?1 ORDER BY seq DESC LIMIT ?2)",
+            params![name, max as i64],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // Newest first.
+    fn list(&mut self, name: &str, count: usize) -> StoreResult<Vec<String>> {
+        let mut statement = self
+            .con
+            .prepare_cached("SELECT value FROM lists WHERE name = ?1 ORDER BY seq DESC LIMIT ?2")?;
+        let values = statement
+            .query_map(params![name, count as i64], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(values)
+    }
+}
+
+impl Store for SqliteStore {
+    fn index_state(&mut self) -> StoreResult<String> {
+        let state: Option<String> = self
+            .con
+            .query_row("SELECT value FROM meta WHERE key = 'STATE'", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(state.unwrap_or_default())
+    }
+
+    fn set_index_state(&mut self, state: IndexState) -> StoreResult<()> {
+        self.con.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('STATE', ?1)",
+            [state.as_str()],
+        )?;
+        Ok(())
+    }
+
+    fn branch_tip(&mut self, branch: &str) -> StoreResult<Option<String>> {
+        Ok(self
+            .con
+            .query_row(
+                "SELECT tip FROM branch_tips WHERE branch = ?1",
+                [branch],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_branch_tip(&mut self, branch: &str, tip: &str) -> StoreResult<()> {
+        self.con.execute(
+            "INSERT OR REPLACE INTO branch_tips (branch, tip) VALUES (?1, ?2)",
+            [branch, tip],
+        )?;
+        Ok(())
+    }
+
+    fn reset_branch(&mut self, branch: &str) -> StoreResult<()> {
+        self.building.remove(&(branch.to_string(), true));
+        self.con
+            .execute("DELETE FROM branch_tips WHERE branch = ?1", [branch])?;
+        self.clear_first_parents(branch)?;
+        self.clear_reachable(branch)
+    }
+
+    fn add_branch_commits(
+        &mut self,
+        branch: &str,
+        delta: bool,
+        commits: &[Oid],
+    ) -> StoreResult<()> {
+        if commits.is_empty() {
+            return Ok(());
+        }
+        let ids = self.assign_commit_ids(commits)?;
+        let key = (branch.to_string(), delta);
+        if !self.building.contains_key(&key) {
+            // Live writes extend what is already there, a delta starts empty.
+            let bits: Vec<u8> = match delta {
+                true => vec![],
+                false => self
+                    .con
+                    .query_row(
+                        "SELECT bits FROM branch_commits WHERE branch = ?1",
+                        [branch],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .unwrap_or_default(),
+            };
+            self.building.insert(key.clone(), bits);
+        }
+        let bits = self.building.get_mut(&key).unwrap();
+        for id in ids {
+            set_bit(bits, id);
+        }
+        Ok(())
+    }
+
+    fn finish_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()> {
+        if let Some(bits) = self.building.remove(&(branch.to_string(), delta)) {
+            self.con.execute(
+                "INSERT OR REPLACE INTO branch_commits (branch, bits) VALUES (?1, ?2)",
+                params![branch, bits],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn discard_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()> {
+        self.building.remove(&(branch.to_string(), delta));
+        if !delta {
+            self.con
+                .execute("DELETE FROM branch_commits WHERE branch = ?1", [branch])?;
+        }
+        Ok(())
+    }
+
+    fn branch_inclusion(
+        &mut self,
+        branches: &[String],
+        commits: &[String],
+    ) -> StoreResult<Vec<bool>> {
+        let oids: Vec<Oid> = commits
+            .iter()
+            .filter_map(|commit| Oid::from_str(commit).ok())
+            .collect();
+        // A commit that was never indexed is in no branch.
+        let ids = self.commit_ids(&oids)?;
+        if commits.is_empty() || oids.len() < commits.len() || ids.iter().any(|id| id.is_none()) {
+            return Ok(vec![commits.is_empty(); branches.len()]);
+        }
+        let mut statement = self
+            .con
+            .prepare_cached("SELECT substr(bits, ?2, 1) FROM branch_commits WHERE branch = ?1")?;
+        let mut commit_exist_matrix: Vec<bool> = vec![];
+        for branch in branches {
+            let mut is_fully_included = true;
+            for id in ids.iter().flatten() {
+                let byte: Option<Vec<u8>> = statement
+                    .query_row(params![branch, (id / 8 + 1) as i64], |row| row.get(0))
+                    .optional()?;
+                let existence = byte
+                    .and_then(|byte| byte.first().copied())
+                    .is_some_and(|byte| byte & (0x80 >> (id % 8)) != 0);
+                if !existence {
+                    is_fully_included = false;
+                    break;
+                }
+            }
+            commit_exist_matrix.push(is_fully_included);
+        }
+        Ok(commit_exist_matrix)
+    }
+
+    fn reachable(&mut self, branch: &str, commit: &str) -> StoreResult<Option<String>> {
+        Ok(self
+            .con
+            .query_row(
+                "SELECT answer FROM reachable WHERE branch = ?1 AND commit_id = ?2",
+                [branch, commit],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn save_reachable(&mut self, branch: &str, commit: &str, answer: &str) -> StoreResult<()> {
+        self.con.execute(
+            "INSERT OR REPLACE INTO reachable (branch, commit_id, answer) VALUES (?1, ?2, ?3)",
+            [branch, commit, answer],
+        )?;
+        Ok(())
+    }
+
+    fn clear_reachable(&mut self, branch: &str) -> StoreResult<()> {
+        self.con
+            .execute("DELETE FROM reachable WHERE branch = ?1", [branch])?;
+        Ok(())
+    }
+
+    fn forget_unreachable(&mut self, branch: &str) -> StoreResult<()> {
+        self.con.execute(
+            "DELETE FROM reachable WHERE branch = ?1 AND answer != '1'",
+            [branch],
+        )?;
+        Ok(())
+    }
+
+    fn save_pr_merges(&mut self, merges: &[(u64, String)]) -> StoreResult<()> {
+        let transaction = self.con.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO pr_merges (pr, commit_id) VALUES (?1, ?2)",
+            )?;
+            for (pr, commit) in merges {
+                insert.execute(params![*pr as i64, commit])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn pr_merge(&mut self, pr: u64) -> StoreResult<Option<String>> {
+        Ok(self
+            .con
+            .query_row(
+                "SELECT commit_id FROM pr_merges WHERE pr = ?1",
+                [pr as i64],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn merges_scanned(&mut self, branch: &str) -> StoreResult<bool> {
+        let scanned: Option<String> = self
+            .con
+            .query_row(
+                "SELECT branch FROM pr_merges_scanned WHERE branch = ?1",
+                [branch],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(scanned.is_some())
+    }
+
+    fn set_merges_scanned(&mut self, branch: &str) -> StoreResult<()> {
+        self.con.execute(
+            "INSERT OR IGNORE INTO pr_merges_scanned (branch) VALUES (?1)",
+            [branch],
+        )?;
+        Ok(())
+    }
+
+    fn forget_pr_merge(&mut self, pr: u64) -> StoreResult<bool> {
+        Ok(self
+            .con
+            .execute("DELETE FROM pr_merges WHERE pr = ?1", [pr])?
+            > 0)
+    }
+
+    fn feed(&mut self, branch: &str) -> StoreResult<Vec<FeedEntry>> {
+        let entries = self.list(&list_name("FEED", &[branch]), FEED_MAX_ENTRIES)?;
+        Ok(entries
+            .iter()
+            .filter_map(|entry| serde_json::from_str(entry).ok())
+            .collect())
+    }
+
+    fn push_feed_entries(&mut self, branch: &str, entries: &[FeedEntry]) -> StoreResult<()> {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .collect();
+        self.push_list(&list_name("FEED", &[branch]), &entries, FEED_MAX_ENTRIES)
+    }
+
+    fn history(&mut self, branch: &str, count: usize) -> StoreResult<Vec<Advance>> {
+        let entries = self.list(&list_name("HISTORY", &[branch]), count)?;
+        Ok(entries
+            .iter()
+            .filter_map(|entry| serde_json::from_str(entry).ok())
+            .collect())
+    }
+
+    fn push_history(&mut self, branch: &str, advance: &Advance) -> StoreResult<()> {
+        self.push_list(
+            &list_name("HISTORY", &[branch]),
+            &[serde_json::to_string(advance).unwrap()],
+            HISTORY_MAX_ENTRIES,
+        )
+    }
+
+    fn arrival(&mut self, branch: &str, commit: &str) -> StoreResult<Option<i64>> {
+        Ok(self
+            .con
+            .query_row(
+                "SELECT at FROM arrivals WHERE branch = ?1 AND commit_id = ?2",
+                [branch, commit],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn save_arrivals(
+        &mut self,
+        branch: &str,
+        arrivals: &[(String, i64)],
+        oldest: i64,
+    ) -> StoreResult<()> {
+        let transaction = self.con.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR IGNORE INTO arrivals (branch, commit_id, at) VALUES (?1, ?2, ?3)",
+            )?;
+            for (commit, at) in arrivals {
+                insert.execute(params![branch, commit, at])?;
+            }
+        }
+        transaction.execute(
+            "DELETE FROM arrivals WHERE branch = ?1 AND at <= ?2",
+            params![branch, oldest],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn lag_samples(&mut self, upstream: &str, branch: &str) -> StoreResult<Vec<i64>> {
+        let lags = self.list(&list_name("LAGS", &[upstream, branch]), LAG_MAX_SAMPLES)?;
+        Ok(lags.iter().filter_map(|lag| lag.parse().ok()).collect())
+    }
+
+    fn push_lag_samples(&mut self, upstream: &str, branch: &str, lags: &[i64]) -> StoreResult<()> {
+        let lags: Vec<String> = lags.iter().map(|lag| lag.to_string()).collect();
+        self.push_list(
+            &list_name("LAGS", &[upstream, branch]),
+            &lags,
+            LAG_MAX_SAMPLES,
+        )
+    }
+
+    fn first_parent_top(&mut self, branch: &str) -> StoreResult<Option<(String, usize)>> {
+        let top: Option<(String, i64)> = self
+            .con
+            .query_row(
+                "SELECT commit_id, position FROM first_parents WHERE branch = ?1
+                 ORDER BY position DESC LIMIT 1",
+                [branch],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(top.map(|(commit, position)| (commit, position as usize)))
+    }
+
+    fn add_first_parents(
+        &mut self,
+        branch: &str,
+        start: usize,
+        commits: &[String],
+    ) -> StoreResult<()> {
+        let transaction = self.con.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO first_parents (branch, position, commit_id)
+                 VALUES (?1, ?2, ?3)",
+            )?;
+            for (offset, commit) in commits.iter().enumerate() {
+                insert.execute(params![branch, (start + offset) as i64, commit])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn clear_first_parents(&mut self, branch: &str) -> StoreResult<()> {
+        self.con
+            .execute("DELETE FROM first_parents WHERE branch = ?1", [branch])?;
+        Ok(())
+    }
+
+    fn first_parent_count(&mut self, branch: &str) -> StoreResult<usize> {
+        let count: i64 = self.con.query_row(
+            "SELECT COUNT(*) FROM first_parents WHERE branch = ?1",
+            [branch],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn first_parent_at(&mut self, branch: &str, position: usize) -> StoreResult<Option<String>> {
+        Ok(self
+            .con
+            .query_row(
+                "SELECT commit_id FROM first_parents WHERE branch = ?1 AND position = ?2",
+                params![branch, position as i64],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn save_watch(&mut self, watch: &Watch) -> StoreResult<()> {
+        self.con.execute(
+            "INSERT OR REPLACE INTO watches (id, watch) VALUES (?1, ?2)",
+            [&watch.id, &serde_json::to_string(watch).unwrap()],
+        )?;
+        Ok(())
+    }
+
+    fn watch(&mut self, id: &str) -> StoreResult<Option<Watch>> {
+        let watch: Option<String> = self
+            .con
+            .query_row("SELECT watch FROM watches WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(watch.and_then(|watch| serde_json::from_str(&watch).ok()))
+    }
+
+    fn watches(&mut self) -> StoreResult<Vec<Watch>> {
+        let mut statement = self.con.prepare_cached("SELECT watch FROM watches")?;
+        let watches: Vec<String> = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(watches
+            .iter()
+            .filter_map(|watch| serde_json::from_str(watch).ok())
+            .collect())
+    }
+
+    fn delete_watch(&mut self, id: &str) -> StoreResult<bool> {
+        let removed = self
+            .con
+            .execute("DELETE FROM watches WHERE id = ?1", [id])?;
+        Ok(removed > 0)
+    }
+}
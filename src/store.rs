@@ -0,0 +1,435 @@
+use std::{
+    env, fmt,
+    sync::{Arc, Mutex},
+};
+
+use git2::Oid;
+
+use crate::{
+    config::IndexState,
+    eta::Advance,
+    feed::FeedEntry,
+    memory_store::{MemoryData, MemoryStore},
+    redis_database::{is_connection_error, RedisConfig, RedisStore},
+    sqlite_store::SqliteStore,
+    watch::Watch,
+};
+
+#[derive(Debug)]
+pub enum StoreError {
+    // The backend cannot be reached right now. It is retried with backoff.
+    Unavailable(String),
+    Failed(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Unavailable(e) => write!(f, "Storage is unavailable: {}", e),
+            StoreError::Failed(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<redis::RedisError> for StoreError {
+    fn from(e: redis::RedisError) -> StoreError {
+        match is_connection_error(&e) {
+            true => StoreError::Unavailable(e.to_string()),
+            false => StoreError::Failed(e.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> StoreError {
+        StoreError::Failed(e.to_string())
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+// Everything the tracker persists. Each thread opens its own store; all of them
+// see the same data. Commit ids are hex strings unless they are `Oid`s.
+pub trait Store: Send {
+    // Fails with `StoreError::Unavailable` while the backend cannot be reached.
+    // Cheap enough to call before every request.
+    fn check(&mut self) -> StoreResult<()> {
+        Ok(())
+    }
+
+    // Called before each indexing pass, so a backend can migrate what older
+    // versions left behind for these branches.
+    fn prepare(&mut self, _branches: &[String]) -> StoreResult<()> {
+        Ok(())
+    }
+
+    // The indexing state shown by `GET /`, empty before the first start.
+    fn index_state(&mut self) -> StoreResult<String>;
+    fn set_index_state(&mut self, state: IndexState) -> StoreResult<()>;
+
+    // The last indexed tip of a branch.
+    fn branch_tip(&mut self, branch: &str) -> StoreResult<Option<String>>;
+    fn set_branch_tip(&mut self, branch: &str, tip: &str) -> StoreResult<()>;
+    // Forgets the tip, the first parents and cached answers of a branch so the
+    // next pass indexes it from scratch.
+    fn reset_branch(&mut self, branch: &str) -> StoreResult<()>;
+
+    // The commits reachable from each branch. They are written in batches
+    // either to the live membership or to a delta that replaces it on finish.
+    fn add_branch_commits(&mut self, branch: &str, delta: bool, commits: &[Oid])
+        -> StoreResult<()>;
+    fn finish_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()>;
+    fn discard_branch_commits(&mut self, branch: &str, delta: bool) -> StoreResult<()>;
+    // For every branch, whether all of the given commits are reachable from it.
+    fn branch_inclusion(
+        &mut self,
+        branches: &[String],
+        commits: &[String],
+    ) -> StoreResult<Vec<bool>>;
+
+    // Answers of the graph reachability engine: "1" for reachable commits,
+    // otherwise the tip the commit was not reachable from.
+    fn reachable(&mut self, branch: &str, commit: &str) -> StoreResult<Option<String>>;
+    fn save_reachable(&mut self, branch: &str, commit: &str, answer: &str) -> StoreResult<()>;
+    fn clear_reachable(&mut self, branch: &str) -> StoreResult<()>;
+    // Drops the negative answers, which only hold for the tip they were given for.
+    fn forget_unreachable(&mut self, branch: &str) -> StoreResult<()>;
+
+    // PR number -> merge commit, and the branches whose whole history was scanned for them.
+    fn save_pr_merges(&mut self, merges: &[(u64, String)]) -> StoreResult<()>;
+    fn pr_merge(&mut self, pr: u64) -> StoreResult<Option<String>>;
+    fn merges_scanned(&mut self, branch: &str) -> StoreResult<bool>;
+    fn set_merges_scanned(&mut self, branch: &str) -> StoreResult<()>;
+    // Drops the merge commit indexed for a PR, so that it is looked up on GitHub.
+    // Returns whether there was one.
+    fn forget_pr_merge(&mut self, pr: u64) -> StoreResult<bool>;
+
+    // Newest first. Entries are pushed oldest first.
+    fn feed(&mut self, branch: &str) -> StoreResult<Vec<FeedEntry>>;
+    fn push_feed_entries(&mut self, branch: &str, entries: &[FeedEntry]) -> StoreResult<()>;
+    fn history(&mut self, branch: &str, count: usize) -> StoreResult<Vec<Advance>>;
+    fn push_history(&mut self, branch: &str, advance: &Advance) -> StoreResult<()>;
+
+    // When a PR merge commit first became reachable from a branch.
+    fn arrival(&mut self, branch: &str, commit: &str) -> StoreResult<Option<i64>>;
+    // Keeps earlier arrivals of the same commit, and drops those before `oldest`.
+    fn save_arrivals(
+        &mut self,
+        branch: &str,
+        arrivals: &[(String, i64)],
+        oldest: i64,
+    ) -> StoreResult<()>;
+    // Seconds merges took to reach `branch` after reaching `upstream`, newest first.
+    fn lag_samples(&mut self, upstream: &str, branch: &str) -> StoreResult<Vec<i64>>;
+    fn push_lag_samples(&mut self, upstream: &str, branch: &str, lags: &[i64]) -> StoreResult<()>;
+
+    // The first-parent history of a branch by position, 0 being the root.
+    fn first_parent_top(&mut self, branch: &str) -> StoreResult<Option<(String, usize)>>;
+    fn add_first_parents(
+        &mut self,
+        branch: &str,
+        start: usize,
+        commits: &[String],
+    ) -> StoreResult<()>;
+    fn clear_first_parents(&mut self, branch: &str) -> StoreResult<()>;
+    fn first_parent_count(&mut self, branch: &str) -> StoreResult<usize>;
+    fn first_parent_at(&mut self, branch: &str, position: usize) -> StoreResult<Option<String>>;
+
+    fn save_watch(&mut self, watch: &Watch) -> StoreResult<()>;
+    fn watch(&mut self, id: &str) -> StoreResult<Option<Watch>>;
+    fn watches(&mut self) -> StoreResult<Vec<Watch>>;
+    fn delete_watch(&mut self, id: &str) -> StoreResult<bool>;
+}
+
+// Where the data lives: Redis when REDIS_URL is set, otherwise an SQLite file
+// at SQLITE_PATH so a single binary can run on its own. STORE=memory keeps
+// everything in the process, for tests and throwaway runs.
+#[derive(Clone, Debug)]
+pub enum StoreConfig {
+    Redis(RedisConfig),
+    Sqlite(String),
+    Memory(Arc<Mutex<MemoryData>>),
+}
+
+impl StoreConfig {
+    pub fn memory() -> StoreConfig {
+        StoreConfig::Memory(Arc::new(Mutex::new(MemoryData::default())))
+    }
+
+    pub fn from_env() -> Result<StoreConfig, String> {
+        if env::var("STORE").is_ok_and(|store| store == "memory") {
+            return Ok(StoreConfig::memory());
+        }
+        Ok(match env::var("REDIS_URL") {
+            Ok(url) => StoreConfig::Redis(RedisConfig::from_env(url)?),
+            Err(_e) => StoreConfig::Sqlite(
+                env::var("SQLITE_PATH").unwrap_or("fast-nixpkgs-tracker.sqlite3".to_string()),
+            ),
+        })
+    }
+
+    pub fn open(&self) -> StoreResult<Box<dyn Store>> {
+        Ok(match self {
+            StoreConfig::Redis(config) => Box::new(RedisStore::open(config)?),
+            StoreConfig::Sqlite(path) => Box::new(SqliteStore::open(path)?),
+            StoreConfig::Memory(data) => Box::new(MemoryStore::open(data.clone())),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use git2::ObjectType;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{feed::FEED_MAX_ENTRIES, watch::Callback};
+
+    fn commit(name: &str) -> Oid {
+        Oid::hash_object(ObjectType::Commit, name.as_bytes()).unwrap()
+    }
+
+    fn hex(commits: &[Oid]) -> Vec<String> {
+        commits.iter().map(Oid::to_string).collect()
+    }
+
+    fn feed_entry(pr: u64) -> FeedEntry {
+        FeedEntry {
+            pr,
+            commit: commit(&format!("m{}", pr)).to_string(),
+            title: format!("PR {}", pr),
+            merged_at: pr as i64,
+            landed_at: pr as i64 + 60,
+        }
+    }
+
+    fn watch(id: &str, pr: u64) -> Watch {
+        Watch {
+            id: id.to_string(),
+            pr,
+            branches: vec!["nixos-unstable".to_string()],
+            callback: Callback::Webhook {
+                url: "https://example.com/hook".to_string(),
+            },
+            created_at: 1_700_000_000,
+            commits: vec![],
+        }
+    }
+
+    fn check_branch_inclusion(store: &mut dyn Store) {
+        let branches = ["master".to_string(), "nixos-unstable".to_string()];
+        let (c0, c1, c2) = (commit("c0"), commit("c1"), commit("c2"));
+        let inclusion = |store: &mut dyn Store, commits: &[Oid]| {
+            store.branch_inclusion(&branches, &hex(commits)).unwrap()
+        };
+        store
+            .add_branch_commits("master", false, &[c0, c1])
+            .unwrap();
+        store
+            .add_branch_commits("nixos-unstable", false, &[c0])
+            .unwrap();
+        store.finish_branch_commits("master", false).unwrap();
+        store
+            .finish_branch_commits("nixos-unstable", false)
+            .unwrap();
+        assert_eq!(inclusion(store, &[c0]), [true, true]);
+        assert_eq!(inclusion(store, &[c0, c1]), [true, false]);
+        // Commits never indexed, or not commit ids at all, are in no branch.
+        assert_eq!(inclusion(store, &[c0, c2]), [false, false]);
+        assert_eq!(
+            store
+                .branch_inclusion(&branches, &["not a commit".to_string()])
+                .unwrap(),
+            [false, false]
+        );
+        // All of no commits are included anywhere.
+        assert_eq!(inclusion(store, &[]), [true, true]);
+
+        // A rebuilt branch only replaces the live one once finished.
+        store
+            .add_branch_commits("nixos-unstable", true, &[c0, c1, c2])
+            .unwrap();
+        assert_eq!(inclusion(store, &[c1]), [true, false]);
+        store.finish_branch_commits("nixos-unstable", true).unwrap();
+        assert_eq!(inclusion(store, &[c1, c2]), [false, true]);
+
+        // A discarded rebuild leaves the live branch alone.
+        store.add_branch_commits("master", true, &[c2]).unwrap();
+        store.discard_branch_commits("master", true).unwrap();
+        store.add_branch_commits("master", false, &[]).unwrap();
+        assert_eq!(inclusion(store, &[c0, c1]), [true, true]);
+        assert_eq!(inclusion(store, &[c2]), [false, true]);
+
+        store.discard_branch_commits("master", false).unwrap();
+        assert_eq!(inclusion(store, &[c0]), [false, true]);
+    }
+
+    // What every backend has to agree on. `open` returns a new handle on the
+    // same data each time, as every thread opens its own store.
+    pub(crate) fn check_conformance(open: impl Fn() -> Box<dyn Store>) {
+        let mut store = open();
+        let mut other = open();
+        let other = other.as_mut();
+        store.check().unwrap();
+        store.prepare(&["master".to_string()]).unwrap();
+
+        assert_eq!(other.index_state().unwrap(), "");
+        store.set_index_state(IndexState::Ready).unwrap();
+        assert_eq!(other.index_state().unwrap(), "READY");
+
+        let (c0, c1) = (commit("c0").to_string(), commit("c1").to_string());
+        assert_eq!(other.branch_tip("master").unwrap(), None);
+        store.set_branch_tip("master", &c0).unwrap();
+        store.set_branch_tip("master", &c1).unwrap();
+        assert_eq!(other.branch_tip("master").unwrap(), Some(c1.clone()));
+
+        check_branch_inclusion(store.as_mut());
+
+        store.save_reachable("master", &c0, "1").unwrap();
+        store.save_reachable("master", &c1, &c0).unwrap();
+        assert_eq!(other.reachable("master", &c0).unwrap(), Some("1".into()));
+        assert_eq!(other.reachable("master", &c1).unwrap(), Some(c0.clone()));
+        assert_eq!(other.reachable("staging", &c0).unwrap(), None);
+        store.forget_unreachable("master").unwrap();
+        assert_eq!(other.reachable("master", &c0).unwrap(), Some("1".into()));
+        assert_eq!(other.reachable("master", &c1).unwrap(), None);
+        store.save_reachable("master", &c1, &c0).unwrap();
+        store.save_reachable("master", &c1, "1").unwrap();
+        store.forget_unreachable("master").unwrap();
+        assert_eq!(other.reachable("master", &c1).unwrap(), Some("1".into()));
+        store.clear_reachable("master").unwrap();
+        assert_eq!(other.reachable("master", &c0).unwrap(), None);
+
+        store
+            .save_pr_merges(&[(1, c0.clone()), (2, c1.clone())])
+            .unwrap();
+        assert_eq!(other.pr_merge(1).unwrap(), Some(c0.clone()));
+        assert_eq!(other.pr_merge(3).unwrap(), None);
+        assert!(!other.merges_scanned("master").unwrap());
+        store.set_merges_scanned("master").unwrap();
+        assert!(other.merges_scanned("master").unwrap());
+        assert!(store.forget_pr_merge(1).unwrap());
+        assert!(!store.forget_pr_merge(1).unwrap());
+        assert!(!store.forget_pr_merge(3).unwrap());
+        assert_eq!(other.pr_merge(1).unwrap(), None);
+        assert_eq!(other.pr_merge(2).unwrap(), Some(c1.clone()));
+
+        assert!(other.feed("master").unwrap().is_empty());
+        store
+            .push_feed_entries("master", &[feed_entry(1), feed_entry(2)])
+            .unwrap();
+        store.push_feed_entries("master", &[feed_entry(3)]).unwrap();
+        let feed: Vec<u64> = other.feed("master").unwrap().iter().map(|e| e.pr).collect();
+        assert_eq!(feed, [3, 2, 1]);
+        let entries: Vec<FeedEntry> = (0..FEED_MAX_ENTRIES as u64 + 5).map(feed_entry).collect();
+        store.push_feed_entries("master", &entries).unwrap();
+        let feed = other.feed("master").unwrap();
+        assert_eq!(feed.len(), FEED_MAX_ENTRIES);
+        assert_eq!(feed[0].pr, FEED_MAX_ENTRIES as u64 + 4);
+        assert_eq!(feed[0].title, format!("PR {}", FEED_MAX_ENTRIES + 4));
+
+        for at in [10, 20, 30] {
+            let advance = Advance {
+                from: format!("from-{}", at),
+                to: format!("to-{}", at),
+                at,
+            };
+            store.push_history("master", &advance).unwrap();
+        }
+        let history = other.history("master", 2).unwrap();
+        let history: Vec<(&str, i64)> = history.iter().map(|a| (a.to.as_str(), a.at)).collect();
+        assert_eq!(history, [("to-30", 30), ("to-20", 20)]);
+        assert!(other.history("staging", 2).unwrap().is_empty());
+
+        store
+            .save_arrivals("nixos-unstable", &[(c0.clone(), 100), (c1.clone(), 50)], 0)
+            .unwrap();
+        // Earlier arrivals are kept, and those before `oldest` dropped.
+        store
+            .save_arrivals("nixos-unstable", &[(c0.clone(), 200)], 60)
+            .unwrap();
+        assert_eq!(other.arrival("nixos-unstable", &c0).unwrap(), Some(100));
+        assert_eq!(other.arrival("nixos-unstable", &c1).unwrap(), None);
+        assert_eq!(other.arrival("master", &c0).unwrap(), None);
+
+        assert!(other
+            .lag_samples("master", "nixos-unstable")
+            .unwrap()
+            .is_empty());
+        store
+            .push_lag_samples("master", "nixos-unstable", &[1, 2])
+            .unwrap();
+        store
+            .push_lag_samples("master", "nixos-unstable", &[3])
+            .unwrap();
+        assert_eq!(
+            other.lag_samples("master", "nixos-unstable").unwrap(),
+            [3, 2, 1]
+        );
+        assert!(other
+            .lag_samples("staging", "nixos-unstable")
+            .unwrap()
+            .is_empty());
+
+        let parents = hex(&[commit("p0"), commit("p1"), commit("p2")]);
+        assert_eq!(other.first_parent_top("master").unwrap(), None);
+        assert_eq!(other.first_parent_count("master").unwrap(), 0);
+        store.add_first_parents("master", 0, &parents[..2]).unwrap();
+        store.add_first_parents("master", 2, &parents[2..]).unwrap();
+        assert_eq!(
+            other.first_parent_top("master").unwrap(),
+            Some((parents[2].clone(), 2))
+        );
+        assert_eq!(other.first_parent_count("master").unwrap(), 3);
+        assert_eq!(
+            other.first_parent_at("master", 1).unwrap(),
+            Some(parents[1].clone())
+        );
+        assert_eq!(other.first_parent_at("master", 3).unwrap(), None);
+        store.clear_first_parents("master").unwrap();
+        assert_eq!(other.first_parent_count("master").unwrap(), 0);
+
+        // Resetting a branch forgets its tip, first parents and answers, but not
+        // its feed or history.
+        store.add_first_parents("master", 0, &parents).unwrap();
+        store.save_reachable("master", &c0, "1").unwrap();
+        store.save_reachable("master", &c1, &c0).unwrap();
+        store.reset_branch("master").unwrap();
+        assert_eq!(other.branch_tip("master").unwrap(), None);
+        assert_eq!(other.first_parent_count("master").unwrap(), 0);
+        assert_eq!(other.reachable("master", &c0).unwrap(), None);
+        assert_eq!(other.reachable("master", &c1).unwrap(), None);
+        assert_eq!(other.feed("master").unwrap().len(), FEED_MAX_ENTRIES);
+        assert_eq!(other.history("master", 5).unwrap().len(), 3);
+
+        assert!(other.watches().unwrap().is_empty());
+        store.save_watch(&watch("a", 1)).unwrap();
+        store.save_watch(&watch("b", 2)).unwrap();
+        let mut updated = watch("a", 1);
+        updated.commits = vec![c0.clone()];
+        store.save_watch(&updated).unwrap();
+        let saved = other.watch("a").unwrap().unwrap();
+        assert_eq!((saved.pr, saved.commits), (1, vec![c0.clone()]));
+        assert!(other.watch("c").unwrap().is_none());
+        let mut ids: Vec<String> = other.watches().unwrap().into_iter().map(|w| w.id).collect();
+        ids.sort();
+        assert_eq!(ids, ["a", "b"]);
+        assert!(store.delete_watch("a").unwrap());
+        assert!(!store.delete_watch("a").unwrap());
+        assert_eq!(other.watches().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn memory_store_conforms() {
+        let config = StoreConfig::memory();
+        check_conformance(|| config.open().unwrap());
+    }
+
+    #[test]
+    fn sqlite_store_conforms() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tracker.sqlite3");
+        let config = StoreConfig::Sqlite(path.to_str().unwrap().to_string());
+        check_conformance(|| config.open().unwrap());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+// Branch -> branches it flows into, following how nixpkgs changes travel:
+// staging -> staging-next -> master -> nixos-unstable-small -> nixos-unstable, etc.
+pub type Topology = BTreeMap<String, Vec<String>>;
+
+pub fn default_topology() -> Topology {
+    [
+        ("staging", vec!["staging-next"]),
+        ("staging-next", vec!["master"]),
+        ("master", vec!["nixpkgs-unstable", "nixos-unstable-small"]),
+        ("nixos-unstable-small", vec!["nixos-unstable"]),
+        ("release-24.05", vec!["nixos-24.05-small"]),
+        ("nixos-24.05-small", vec!["nixos-24.05"]),
+    ]
+    .into_iter()
+    .map(|(upstream, downstreams)| {
+        (
+            upstream.to_string(),
+            downstreams.into_iter().map(String::from).collect(),
+        )
+    })
+    .collect()
+}
+
+pub fn validate_topology(topology: &Topology) -> Result<(), String> {
+    // Depth first search keeping the current path to spot cycles.
+    fn visit<'a>(
+        topology: &'a Topology,
+        branch: &'a str,
+        path: &mut Vec<&'a str>,
+        done: &mut BTreeSet<&'a str>,
+    ) -> Result<(), String> {
+        if done.contains(branch) {
+            return Ok(());
+        }
+        if path.contains(&branch) {
+            path.push(branch);
+            return Err(format!(
+                "Branch topology has a cycle: {}",
+                path.join(" -> ")
+            ));
+        }
+        path.push(branch);
+        for downstream in topology.get(branch).into_iter().flatten() {
+            visit(topology, downstream, path, done)?;
+        }
+        path.pop();
+        done.insert(branch);
+        Ok(())
+    }
+
+    let mut done = BTreeSet::new();
+    for branch in topology.keys() {
+        visit(topology, branch, &mut vec![], &mut done)?;
+    }
+    Ok(())
+}
+
+// The nearest tracked branches downstream of `branch`, looking through untracked ones.
+fn tracked_downstreams(topology: &Topology, branch: &str, tracked: &[String]) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+    let mut pending: Vec<&str> = topology
+        .get(branch)
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect();
+    let mut seen = BTreeSet::new();
+    while let Some(next) = pending.pop() {
+        if !seen.insert(next) {
+            continue;
+        }
+        if tracked.iter().any(|branch| branch == next) {
+            found.insert(next.to_string());
+        } else {
+            pending.extend(topology.get(next).into_iter().flatten().map(String::as_str));
+        }
+    }
+    found
+}
+
+// Every tracked branch whose changes eventually flow into `branch`, directly or not.
+pub fn tracked_upstreams(topology: &Topology, branch: &str, tracked: &[String]) -> Vec<String> {
+    fn flows_into(topology: &Topology, from: &str, to: &str, seen: &mut BTreeSet<String>) -> bool {
+        topology.get(from).into_iter().flatten().any(|next| {
+            next == to || (seen.insert(next.clone()) && flows_into(topology, next, to, seen))
+        })
+    }
+
+    tracked
+        .iter()
+        .filter(|upstream| *upstream != branch)
+        .filter(|upstream| flows_into(topology, upstream, branch, &mut BTreeSet::new()))
+        .cloned()
+        .collect()
+}
+
+// Where a PR is in the pipeline.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Pipeline {
+    // Furthest tracked branches the PR has reached.
+    pub current: Vec<String>,
+    // Tracked branches the PR is expected to reach next. Empty before the PR
+    // reached any branch: where it enters depends on its base branch.
+    pub next: Vec<String>,
+    // Whether the PR reached every tracked branch downstream of where it entered.
+    pub complete: bool,
+}
+
+pub fn pipeline_position(
+    topology: &Topology,
+    branches: &[String],
+    included_in: &[bool],
+) -> Pipeline {
+    let included: Vec<String> = branches
+        .iter()
+        .zip(included_in)
+        .filter(|(_, included)| **included)
+        .map(|(branch, _)| branch.clone())
+        .collect();
+    if included.is_empty() {
+        return Pipeline {
+            current: vec![],
+            next: vec![],
+            complete: false,
+        };
+    }
+    let downstreams: BTreeMap<&String, BTreeSet<String>> = branches
+        .iter()
+        .map(|branch| (branch, tracked_downstreams(topology, branch, branches)))
+        .collect();
+
+    let current: Vec<String> = included
+        .iter()
+        .filter(|branch| {
+            !downstreams[branch]
+                .iter()
+                .any(|down| included.contains(down))
+        })
+        .cloned()
+        .collect();
+    let next: BTreeSet<String> = current
+        .iter()
+        .flat_map(|branch| downstreams[branch].iter())
+        .filter(|down| !included.contains(down))
+        .cloned()
+        .collect();
+    // Keep the configured branch order, which is what clients display.
+    let next: Vec<String> = branches
+        .iter()
+        .filter(|branch| next.contains(*branch))
+        .cloned()
+        .collect();
+    Pipeline {
+        complete: next.is_empty(),
+        current,
+        next,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(edges: &[(&str, &[&str])]) -> Topology {
+        edges
+            .iter()
+            .map(|(upstream, downstreams)| {
+                (
+                    upstream.to_string(),
+                    downstreams.iter().map(|down| down.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn names(branches: &[&str]) -> Vec<String> {
+        branches.iter().map(|branch| branch.to_string()).collect()
+    }
+
+    fn position(tracked: &[&str], included: &[&str]) -> Pipeline {
+        let tracked = names(tracked);
+        let included_in: Vec<bool> = tracked
+            .iter()
+            .map(|branch| included.contains(&branch.as_str()))
+            .collect();
+        pipeline_position(&default_topology(), &tracked, &included_in)
+    }
+
+    #[test]
+    fn default_topology_is_valid() {
+        assert_eq!(validate_topology(&default_topology()), Ok(()));
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let cycle = topology(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])]);
+        assert_eq!(
+            validate_topology(&cycle),
+            Err("Branch topology has a cycle: a -> b -> c -> a".to_string())
+        );
+        let self_loop = topology(&[("a", &["a"])]);
+        assert!(validate_topology(&self_loop).is_err());
+    }
+
+    #[test]
+    fn shared_downstreams_and_unknown_branches_are_valid() {
+        let diamond = topology(&[("a", &["b", "c"]), ("b", &["d"]), ("c", &["d"])]);
+        assert_eq!(validate_topology(&diamond), Ok(()));
+        // "d" and "e" flow nowhere and have no entry of their own.
+        let leaves = topology(&[("a", &["d", "e"])]);
+        assert_eq!(validate_topology(&leaves), Ok(()));
+    }
+
+    #[test]
+    fn pr_midway_through_the_flow() {
+        let tracked = [
+            "staging",
+            "staging-next",
+            "master",
+            "nixos-unstable-small",
+            "nixos-unstable",
+        ];
+        assert_eq!(
+            position(&tracked, &["staging", "staging-next", "master"]),
+            Pipeline {
+                current: names(&["master"]),
+                next: names(&["nixos-unstable-small"]),
+                complete: false,
+            }
+        );
+        assert_eq!(
+            position(&tracked, &tracked[2..]),
+            Pipeline {
+                current: names(&["nixos-unstable"]),
+                next: vec![],
+                complete: true,
+            }
+        );
+    }
+
+    #[test]
+    fn untracked_branches_are_looked_through() {
+        assert_eq!(
+            position(&["master", "nixos-unstable"], &["master"]),
+            Pipeline {
+                current: names(&["master"]),
+                next: names(&["nixos-unstable"]),
+                complete: false,
+            }
+        );
+    }
+
+    #[test]
+    fn branches_missing_from_the_topology_stand_alone() {
+        assert_eq!(
+            position(
+                &["master", "haskell-updates"],
+                &["master", "haskell-updates"]
+            ),
+            Pipeline {
+                current: names(&["master", "haskell-updates"]),
+                next: vec![],
+                complete: true,
+            }
+        );
+    }
+
+    #[test]
+    fn pr_in_no_branch_has_no_position() {
+        assert_eq!(
+            position(&["staging", "master", "release-24.05"], &[]),
+            Pipeline {
+                current: vec![],
+                next: vec![],
+                complete: false,
+            }
+        );
+    }
+
+    #[test]
+    fn upstreams_are_found_through_untracked_branches() {
+        let tracked = names(&["staging", "master", "nixos-unstable", "nixos-24.05"]);
+        assert_eq!(
+            tracked_upstreams(&default_topology(), "nixos-unstable", &tracked),
+            names(&["staging", "master"])
+        );
+        assert!(tracked_upstreams(&default_topology(), "staging", &tracked).is_empty());
+    }
+}
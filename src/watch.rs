@@ -0,0 +1,463 @@
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+use crate::{
+    auth::WatchAccess,
+    config::tracked_branches,
+    github::{fetch_pr, PrState},
+    reachability::commit_inclusion,
+    scheduler::IndexEvent,
+    store::{Store, StoreConfig},
+    web::{store_failure, with_store, AppState},
+};
+
+// A request to be told once the merged `pr` is reachable from all `branches`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Watch {
+    pub id: String,
+    pub pr: u64,
+    pub branches: Vec<String>,
+    pub callback: Callback,
+    pub created_at: u64,
+    // The commit GitHub reported the PR merged as, or its head commits when no
+    // merge commit was given. They no longer change, so GitHub is not asked again.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commits: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Callback {
+    // POSTs a JSON document describing the event.
+    Webhook { url: String },
+    // POSTs a plain text message, as understood by ntfy.sh and similar services.
+    Ntfy { url: String, token: Option<String> },
+}
+
+impl Callback {
+    fn url(&self) -> &str {
+        match self {
+            Callback::Webhook { url } => url,
+            Callback::Ntfy { url, .. } => url,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WatchRequest {
+    pr: u64,
+    branches: Vec<String>,
+    callback: Callback,
+}
+
+// How an ended watch is reported to its subscriber.
+#[derive(Clone, Copy, Debug)]
+enum WatchEvent {
+    Included,
+    // The PR was closed without being merged.
+    Closed,
+    // WATCH_TTL passed first.
+    Expired,
+}
+
+impl WatchEvent {
+    fn name(self) -> &'static str {
+        match self {
+            WatchEvent::Included => "pr_included",
+            WatchEvent::Closed => "pr_closed",
+            WatchEvent::Expired => "watch_expired",
+        }
+    }
+
+    fn message(self, watch: &Watch) -> String {
+        match self {
+            WatchEvent::Included => {
+                format!("PR #{} has reached {}", watch.pr, watch.branches.join(", "))
+            }
+            WatchEvent::Closed => format!("PR #{} was closed without being merged", watch.pr),
+            WatchEvent::Expired => format!(
+                "Stopped waiting for PR #{} to reach {}",
+                watch.pr,
+                watch.branches.join(", ")
+            ),
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            WatchEvent::Included => "tada",
+            WatchEvent::Closed => "x",
+            WatchEvent::Expired => "hourglass",
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Seconds a watch is kept waiting for its PR.
+fn watch_ttl() -> u64 {
+    env::var("WATCH_TTL")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(30 * 24 * 3600)
+}
+
+fn max_watches() -> usize {
+    env::var("MAX_WATCHES")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(1000)
+}
+
+// Lets callbacks reach private and loopback addresses, for trackers that only
+// notify services on their own network.
+fn private_callbacks() -> bool {
+    matches!(env::var("WATCH_PRIVATE_CALLBACKS"), Ok(val) if val == "true")
+}
+
+// Callbacks must not reach the tracker's own host or network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || first == 0
+                // 100.64.0.0/10, carrier-grade NAT.
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // fc00::/7 unique local and fe80::/10 link-local addresses.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+// Resolves the host of a callback url, failing unless every address is public.
+async fn resolve_callback(url: &str) -> Result<(String, SocketAddr), String> {
+    let url = reqwest::Url::parse(url).map_err(|e| format!("Invalid callback url: {}", e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Callback url must be http(s)".to_string());
+    }
+    let host = url
+        .host_str()
+        .ok_or("Callback url has no host")?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let addrs = web::block(move || url.socket_addrs(|| None))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Failed to resolve callback host: {}", e))?;
+    match addrs.first() {
+        Some(addr) if private_callbacks() || addrs.iter().all(|addr| is_public(addr.ip())) => {
+            Ok((host, *addr))
+        }
+        Some(_) => Err("Callback url must resolve to a public address".to_string()),
+        None => Err("Failed to resolve callback host".to_string()),
+    }
+}
+
+// A client that can only reach the address the callback host was checked to
+// resolve to, so DNS cannot be pointed elsewhere between check and request.
+async fn callback_client(url: &str) -> Result<reqwest::Client, String> {
+    let (host, addr) = resolve_callback(url).await?;
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&host, addr)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+fn pr_url(pr: u64) -> String {
+    format!("https://github.com/NixOS/nixpkgs/pull/{}", pr)
+}
+
+#[post("/watch")]
+async fn create_watch(
+    _access: WatchAccess,
+    data: web::Data<AppState>,
+    request: web::Json<WatchRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let tracked = tracked_branches();
+    if request.branches.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "detail": "At least one target branch is required",
+        }));
+    }
+    if let Some(branch) = request.branches.iter().find(|b| !tracked.contains(b)) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "detail": format!("Branch {} is not tracked", branch),
+        }));
+    }
+    if let Err(detail) = resolve_callback(request.callback.url()).await {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "detail": detail,
+        }));
+    }
+    let count = with_store(&data, |store| store.watches().map(|watches| watches.len())).await;
+    match count {
+        Ok(count) if count >= max_watches() => {
+            return HttpResponse::TooManyRequests().json(json!({
+                "success": false,
+                "detail": "Too many watches",
+            }))
+        }
+        Ok(_) => {}
+        Err(e) => return store_failure(&e),
+    }
+
+    // Ids are random so that nobody can enumerate or delete other people's watches.
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    let watch = Watch {
+        id: hex::encode(id),
+        pr: request.pr,
+        branches: request.branches,
+        callback: request.callback,
+        created_at: now(),
+        commits: vec![],
+    };
+    let record = watch.clone();
+    let saved = with_store(&data, move |store| store.save_watch(&record)).await;
+    match saved {
+        Ok(_) => {
+            info!(
+                id = watch.id,
+                pr = watch.pr,
+                branches = ?watch.branches,
+                "Created watch"
+            );
+            HttpResponse::Created().json(json!({ "success": true, "watch": watch }))
+        }
+        Err(e) => store_failure(&e),
+    }
+}
+
+#[get("/watch/{id}")]
+async fn get_watch(data: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
+    let watch = with_store(&data, move |store| store.watch(&id)).await;
+    match watch {
+        Ok(Some(watch)) => HttpResponse::Ok().json(json!({ "success": true, "watch": watch })),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "detail": "No such watch. It may already have fired.",
+        })),
+        Err(e) => store_failure(&e),
+    }
+}
+
+#[delete("/watch/{id}")]
+async fn remove_watch(data: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
+    let removed = with_store(&data, move |store| store.delete_watch(&id)).await;
+    match removed {
+        Ok(true) => HttpResponse::Ok().json(json!({ "success": true })),
+        Ok(false) => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "detail": "No such watch",
+        })),
+        Err(e) => store_failure(&e),
+    }
+}
+
+async fn notify(watch: &Watch, event: WatchEvent) -> Result<(), String> {
+    let client = callback_client(watch.callback.url()).await?;
+    let request = match &watch.callback {
+        Callback::Webhook { url } => client.post(url).json(&json!({
+            "event": event.name(),
+            "watch_id": watch.id,
+            "pr": watch.pr,
+            "branches": watch.branches,
+            "url": pr_url(watch.pr),
+        })),
+        Callback::Ntfy { url, token } => {
+            let request = client
+                .post(url)
+                .header("Title", format!("nixpkgs PR #{}", watch.pr))
+                .header("Click", pr_url(watch.pr))
+                .header("Tags", event.tag())
+                .body(event.message(watch));
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+        }
+    };
+    request
+        .header("User-Agent", "fast-nixpkgs-tracker")
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Reports how a watch ended and removes it. Failed callbacks are retried after
+// the next indexing pass, until the watch expires.
+async fn finish(store: &Mutex<Box<dyn Store>>, watch: &Watch, event: WatchEvent) {
+    match notify(watch, event).await {
+        Ok(_) => info!(event = event.name(), "Notified watcher."),
+        Err(e) if !matches!(event, WatchEvent::Expired) => {
+            warn!(error = %e, "Failed to notify watcher. Retrying next pass.");
+            return;
+        }
+        Err(e) => warn!(error = %e, "Failed to notify watcher of its expiry."),
+    }
+    let _ = store.lock().unwrap().delete_watch(&watch.id);
+}
+
+// Drops expired watches and checks those affected by the branches that just
+// moved. A watch fires once and is removed.
+#[instrument(skip(store, github_token))]
+async fn evaluate_watches(store: &Mutex<Box<dyn Store>>, github_token: &str, updated: &[String]) {
+    let watches = store.lock().unwrap().watches();
+    let watches = match watches {
+        Ok(watches) => watches,
+        Err(e) => {
+            warn!(error = %e, "Failed to load watches");
+            return;
+        }
+    };
+    let now = now();
+    for watch in &watches {
+        let span = info_span!("watch", id = watch.id, pr = watch.pr);
+        async {
+            if now >= watch.created_at + watch_ttl() {
+                finish(store, watch, WatchEvent::Expired).await;
+                return;
+            }
+            if !watch.branches.iter().any(|branch| updated.contains(branch)) {
+                return;
+            }
+            // A merge commit from the local index stands for the whole PR.
+            let merge_commit = store.lock().unwrap().pr_merge(watch.pr).unwrap_or_default();
+            let commits = match merge_commit {
+                Some(merge_commit) => vec![merge_commit],
+                None if !watch.commits.is_empty() => watch.commits.clone(),
+                None => match fetch_pr(github_token, watch.pr).await {
+                    Ok(PrState::Closed { merged: false, .. }) => {
+                        finish(store, watch, WatchEvent::Closed).await;
+                        return;
+                    }
+                    // Squashed and rebased PRs land as new commits, so their
+                    // head commits never reach any branch.
+                    Ok(PrState::Closed {
+                        merge_commit: Some(merge_commit),
+                        ..
+                    }) => {
+                        let resolved = Watch {
+                            commits: vec![merge_commit.clone()],
+                            ..watch.clone()
+                        };
+                        let _ = store.lock().unwrap().save_watch(&resolved);
+                        vec![merge_commit]
+                    }
+                    Ok(PrState::Closed { commits, .. }) if !commits.is_empty() => {
+                        let resolved = Watch {
+                            commits: commits.clone(),
+                            ..watch.clone()
+                        };
+                        let _ = store.lock().unwrap().save_watch(&resolved);
+                        commits
+                    }
+                    Ok(_) => return,
+                    Err(e) => {
+                        warn!(error = %e, "Failed to fetch PR");
+                        return;
+                    }
+                },
+            };
+            match commit_inclusion(store, &watch.branches, &commits) {
+                Ok(included) if included.iter().all(|included| *included) => {
+                    finish(store, watch, WatchEvent::Included).await;
+                }
+                Ok(_) => {}
+                Err(e) => warn!(error = %e, "Failed to check the watched branches"),
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+// Runs on its own thread with its own runtime so slow callbacks never hold up
+// the indexer or the web server.
+pub fn run_notifier(
+    store_config: StoreConfig,
+    github_token: Arc<Mutex<String>>,
+    events: Receiver<IndexEvent>,
+) {
+    let store = Mutex::new(
+        store_config
+            .open()
+            .unwrap_or_else(|e| panic!("Failed to open the store: {}", e)),
+    );
+    let system = actix_web::rt::System::new();
+    for event in events {
+        match event {
+            IndexEvent::PassFinished { branches } => {
+                let github_token = github_token.lock().unwrap().clone();
+                system.block_on(evaluate_watches(&store, &github_token, &branches));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_callback_targets() {
+        for ip in ["140.82.112.3", "2606:4700::6810:85e5", "100.128.0.1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
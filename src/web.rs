@@ -1,17 +1,66 @@
-use std::{error::Error, sync::Mutex, time::Instant};
+use std::{
+    collections::HashMap,
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
 use actix_cors::Cors;
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use octocrab::{models::IssueState, Octocrab};
-use redis::{Commands, Connection};
+use actix_web::{dev::ServerHandle, get, web, App, HttpResponse, HttpServer, Responder};
+use futures_util::stream;
+use git2::{Oid, Repository};
 use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{instrument, warn};
+use tracing_actix_web::TracingLogger;
 
-use crate::{config::CACHED_BRANCHES, redis_database::open_redis_connection};
+use crate::{
+    admin::admin_scope,
+    auth::JwtAuth,
+    config::{current_config, REPO_PATH},
+    eta::{estimate_arrivals, Eta},
+    feed::branch_feed,
+    github::{fetch_pr, PrState},
+    indexer::branch_landing_commit,
+    maintenance::{maintenance_metrics, maintenance_status},
+    merges::{pr_commits, staging_cycle, StagingCycle},
+    reachability::commit_inclusion,
+    scheduler::{subscribe_index_events, IndexEvent, IndexerCommand},
+    shutdown::shutdown_requested,
+    store::{Store, StoreConfig, StoreError, StoreResult},
+    topology::{pipeline_position, Pipeline},
+    watch::{create_watch, get_watch, remove_watch},
+    webhook::{github_webhook, WEBHOOK_PAYLOAD_LIMIT},
+};
+
+// How long a PR lookup is shared. Every event stream of a PR recomputes its
+// status after the same indexing pass; they share one GitHub call.
+const LOOKUP_TTL: Duration = Duration::from_secs(10);
+
+// The last lookup of a PR and when it was made. Locked across the lookup, so
+// concurrent requests for the same PR wait for it instead of asking again.
+type PrLookup = Arc<tokio::sync::Mutex<Option<(Instant, Result<PrState, String>)>>>;
 
 // This struct represents state
-struct AppState {
-    app_redis_connection: Mutex<Connection>,
-    github_token: Mutex<String>,
+pub struct AppState {
+    // Only locked on the blocking thread pool, see `with_store`.
+    pub store: Mutex<Box<dyn Store>>,
+    pub github_token: Arc<Mutex<String>>,
+    pub indexer: Sender<IndexerCommand>,
+    pub index_events: broadcast::Sender<IndexEvent>,
+    pub pr_lookups: Mutex<HashMap<u64, PrLookup>>,
+}
+
+impl AppState {
+    // Drops the shared lookup of one PR, or of all of them. Returns how many went away.
+    pub fn forget_lookups(&self, pr: Option<u64>) -> usize {
+        let mut lookups = self.pr_lookups.lock().unwrap();
+        match pr {
+            Some(pr) => lookups.remove(&pr).map_or(0, |_| 1),
+            None => lookups.drain().count(),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -20,177 +69,421 @@ struct PrStatusObj {
     detail: String,
     pr: u64,
     commits: Vec<String>,
+    merge_commit: String,
     included_branches: Vec<String>,
     included_in: Vec<bool>,
+    landing_commits: Vec<Option<String>>,
+    pipeline: Option<Pipeline>,
+    eta: Vec<Eta>,
+    staging_cycle: Option<StagingCycle>,
     latest_commit: String,
     network_execution_time: String,
     redis_execution_time: String,
 }
 
+// While the store is down a route could only answer with errors or empty
+// results. Tell clients to come back instead.
+pub fn store_failure(e: &StoreError) -> HttpResponse {
+    let body = json!({ "success": false, "detail": e.to_string() });
+    match e {
+        StoreError::Unavailable(_) => HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "5"))
+            .json(body),
+        StoreError::Failed(_) => HttpResponse::InternalServerError().json(body),
+    }
+}
+
+// Runs `f` against the store on the blocking thread pool, so that Redis round
+// trips and reconnects, made under the store lock, never stall the async workers.
+pub async fn with_store<T, F>(data: &web::Data<AppState>, f: F) -> StoreResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut dyn Store) -> StoreResult<T> + Send + 'static,
+{
+    let data = data.clone();
+    web::block(move || f(data.store.lock().unwrap().as_mut()))
+        .await
+        .map_err(|e| StoreError::Failed(e.to_string()))?
+}
+
 #[get("/")]
 async fn index(data: web::Data<AppState>) -> impl Responder {
-    let mut con = data.app_redis_connection.lock().unwrap();
-    let state: String = con.get("STATE").unwrap();
-    HttpResponse::Ok().body(state)
+    let state = with_store(&data, |store| store.index_state()).await;
+    match state {
+        Ok(state) => HttpResponse::Ok().body(state),
+        Err(e) => store_failure(&e),
+    }
+}
+
+#[get("/status")]
+async fn get_status(data: web::Data<AppState>) -> impl Responder {
+    let state = with_store(&data, |store| store.index_state()).await;
+    match state {
+        Ok(state) => HttpResponse::Ok().json(json!({
+            "state": state,
+            "maintenance": maintenance_status(),
+        })),
+        Err(e) => store_failure(&e),
+    }
+}
+
+#[get("/metrics")]
+async fn get_metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(maintenance_metrics())
 }
 
 #[get("/pr/{id}")]
+#[instrument(skip(data, pr), fields(pr = *pr))]
 async fn get_pr_detail(data: web::Data<AppState>, pr: web::Path<u64>) -> impl Responder {
-    let pr_number: u64 = pr.into_inner();
-    let mut con = data.app_redis_connection.lock().unwrap();
-    let github_token = data.github_token.lock().unwrap();
-    let state: String = con.get("STATE").unwrap();
-
-    if state.to_string().contains("READY") {
-        let mut start = Instant::now();
-        let octocrab = match github_token.len() > 0 {
-            true => Octocrab::builder()
-                .personal_token(github_token.to_string())
-                .build()
-                .unwrap(),
-            false => Octocrab::builder().build().unwrap(),
-        };
-        let pr = octocrab.pulls("NixOS", "nixpkgs").get(pr_number).await;
-
-        if pr.is_ok() {
-            let pr_value = pr.unwrap();
-            match pr_value.state {
-                Some(state) => match state {
-                    IssueState::Open => web::Json(PrStatusObj {
-                        success: true,
-                        detail: "".to_string(),
-                        pr: pr_number,
-                        commits: vec![],
-                        included_branches: vec![],
-                        included_in: vec![],
-                        latest_commit: "".to_string(),
-                        network_execution_time: "".to_string(),
-                        redis_execution_time: "".to_string(),
-                    }),
-                    IssueState::Closed => match pr_value.commits_url {
-                        Some(_) => {
-                            let client = reqwest::Client::new();
-
-                            let response = match (github_token.len() > 0) {
-                                _true => {
-                                    client
-                                        .get(format!(
-                                    "https://api.github.com/repos/NixOS/nixpkgs/pulls/{}/commits",
-                                    pr_number
-                                ))
-                                        .header("Accept", "application/json")
-                                        .header("Authorization", format!("Bearer {}", github_token))
-                                        .header("User-Agent", "Rust")
-                                        .send()
-                                        .await
-                                        .unwrap()
-                                        .json::<serde_json::Value>()
-                                        .await
-                                }
-                                _ => {
-                                    client
-                                        .get(format!(
-                                    "https://api.github.com/repos/NixOS/nixpkgs/pulls/{}/commits",
-                                    pr_number
-                                ))
-                                        .header("Accept", "application/json")
-                                        .header("User-Agent", "Rust")
-                                        .send()
-                                        .await
-                                        .unwrap()
-                                        .json::<serde_json::Value>()
-                                        .await
-                                }
-                            };
-                            if response.is_ok() {
-                                let network_duration = start.elapsed();
-                                start = Instant::now();
-                                let json = response.unwrap();
-                                let commits = json.as_array().unwrap();
-                                let mut commits_vector = vec![];
-                                for commit in commits {
-                                    commits_vector.push(commit["sha"].clone().to_string());
-                                }
-                                let mut commit_exist_matrix: Vec<bool> = vec![];
-                                for (pos, branch) in CACHED_BRANCHES.iter().enumerate() {
-                                    let mut isFullyIncluded = true;
-                                    for (_pos_inner, commit) in commits.iter().enumerate() {
-                                        let commit_sha1 =
-                                            commit["sha"].clone().to_string().replace("\"", "");
-                                        let existence: bool = con
-                                            .sismember(branch.to_uppercase(), commit_sha1)
-                                            .unwrap();
-                                        if !existence {
-                                            isFullyIncluded = false
-                                        };
-                                    }
-                                    commit_exist_matrix.push(isFullyIncluded);
-                                }
-                                let redis_duration = start.elapsed();
-                                let latest_commit: String = con.get("LAST_MASTER_COMMIT").unwrap();
-                                web::Json(PrStatusObj {
-                                    success: true,
-                                    detail: "".to_string(),
-                                    commits: commits_vector,
-                                    pr: pr_number,
-                                    included_branches: CACHED_BRANCHES
-                                        .to_vec()
-                                        .into_iter()
-                                        .map(String::from)
-                                        .collect(),
-                                    included_in: commit_exist_matrix,
-                                    latest_commit: latest_commit,
-                                    network_execution_time: format!("{:?}", network_duration),
-                                    redis_execution_time: format!("{:?}", redis_duration),
-                                })
-                            } else {
-                                web::Json(PrStatusObj {
-                                    success: false,
-                                    detail: format!("Failed to fetch data from GitHub"),
-                                    commits: vec![],
-                                    pr: pr_number,
-                                    included_branches: vec![],
-                                    included_in: vec![],
-                                    latest_commit: "".to_string(),
-                                    network_execution_time: "".to_string(),
-                                    redis_execution_time: "".to_string(),
-                                })
-                            }
-                        }
-                        None => todo!(),
-                    },
-                    _ => panic!("Unexpected pr state, should be OPEN or CLOSED"),
-                },
-                None => panic!("Unexpected pr state, should not be NONE"),
+    match pr_status(&data, pr.into_inner(), &mut None).await {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => store_failure(&e),
+    }
+}
+
+// How often an idle event stream sends a comment line, so proxies keep it open.
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+struct PrEventStream {
+    data: web::Data<AppState>,
+    pr: u64,
+    events: broadcast::Receiver<IndexEvent>,
+    // The PR once it is known to be closed; its commits no longer change.
+    known: Option<PrState>,
+    last_sent: Option<(bool, Vec<String>, Vec<bool>)>,
+}
+
+impl PrEventStream {
+    // Recomputes the status and renders it as an SSE event if anything changed.
+    // A store outage sends nothing; the next pass tries again.
+    async fn next_status(&mut self) -> Option<web::Bytes> {
+        match pr_status(&self.data, self.pr, &mut self.known).await {
+            Ok(status) => self.render(status),
+            Err(e) => {
+                warn!(error = %e, "Failed to compute PR status");
+                None
             }
-        } else {
-            let result: octocrab::Error = pr.unwrap_err();
-            let mut source = result.source().unwrap().to_string();
+        }
+    }
+
+    fn render(&mut self, status: PrStatusObj) -> Option<web::Bytes> {
+        let key = (
+            status.success,
+            status.included_branches.clone(),
+            status.included_in.clone(),
+        );
+        if self.last_sent.as_ref() == Some(&key) {
+            return None;
+        }
+        self.last_sent = Some(key);
+        Some(web::Bytes::from(format!(
+            "event: status\ndata: {}\n\n",
+            serde_json::to_string(&status).unwrap()
+        )))
+    }
+}
 
-            if (source.contains("rate limit")) {
-                source = "API rate limit".to_string();
+// Streams a new PrStatusObj every time an indexing pass changes where the PR is included.
+#[get("/pr/{id}/events")]
+#[instrument(skip(data, pr), fields(pr = *pr))]
+async fn get_pr_events(data: web::Data<AppState>, pr: web::Path<u64>) -> impl Responder {
+    let mut state = PrEventStream {
+        events: data.index_events.subscribe(),
+        data,
+        pr: pr.into_inner(),
+        known: None,
+        last_sent: None,
+    };
+    let first = match pr_status(&state.data, state.pr, &mut state.known).await {
+        Ok(status) => state.render(status),
+        Err(e) => return store_failure(&e),
+    };
+    let body = stream::unfold((state, first), |(mut state, first)| async move {
+        if let Some(status) = first {
+            return Some((Ok::<_, actix_web::Error>(status), (state, None)));
+        }
+        loop {
+            match actix_web::rt::time::timeout(SSE_KEEP_ALIVE, state.events.recv()).await {
+                Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => {
+                    if let Some(status) = state.next_status().await {
+                        return Some((Ok(status), (state, None)));
+                    }
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+                // Let the graceful shutdown complete instead of waiting for clients to leave.
+                Err(_elapsed) if shutdown_requested() => return None,
+                Err(_elapsed) => {
+                    return Some((
+                        Ok(web::Bytes::from_static(b": keep-alive\n\n")),
+                        (state, None),
+                    ))
+                }
             }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+// Resolves a PR from the local merge index, falling back to GitHub for open PRs
+// and PRs that were squashed or rebased. Only a failing store is an error,
+// what GitHub answers is part of the result.
+async fn lookup_pr(
+    data: &web::Data<AppState>,
+    github_token: &str,
+    pr_number: u64,
+) -> StoreResult<Result<PrState, String>> {
+    let merge_commit = with_store(data, move |store| store.pr_merge(pr_number)).await?;
+    if let Some(merge_commit) = merge_commit {
+        let lookup = merge_commit.clone();
+        let commits = web::block(move || {
+            Repository::open(REPO_PATH).and_then(|repo| pr_commits(&repo, &lookup))
+        })
+        .await;
+        match commits {
+            Ok(Ok(commits)) => {
+                return Ok(Ok(PrState::Merged {
+                    merge_commit,
+                    commits,
+                }))
+            }
+            Ok(Err(e)) => warn!(error = %e, merge_commit, "Failed to read PR commits from git"),
+            Err(e) => warn!(error = %e, merge_commit, "Failed to read PR commits from git"),
+        }
+    }
+    Ok(fetch_pr(github_token, pr_number).await)
+}
 
-            web::Json(PrStatusObj {
-                success: false,
-                detail: format!("{}", source),
-                commits: vec![],
-                pr: pr_number,
-                included_branches: vec![],
-                included_in: vec![],
-                latest_commit: "".to_string(),
-                network_execution_time: "".to_string(),
-                redis_execution_time: "".to_string(),
-            })
+// Shares one `lookup_pr` per PR among the requests made within LOOKUP_TTL.
+// Store failures are not shared, the next request tries again.
+async fn shared_lookup(
+    data: &web::Data<AppState>,
+    github_token: &str,
+    pr_number: u64,
+) -> StoreResult<Result<PrState, String>> {
+    let lookup = {
+        let mut lookups = data.pr_lookups.lock().unwrap();
+        // Forget old lookups nobody is waiting for.
+        lookups.retain(|_, lookup| {
+            Arc::strong_count(lookup) > 1
+                || lookup.try_lock().map_or(true, |lookup| {
+                    lookup
+                        .as_ref()
+                        .is_some_and(|(at, _)| at.elapsed() < LOOKUP_TTL)
+                })
+        });
+        lookups.entry(pr_number).or_default().clone()
+    };
+    let mut lookup = lookup.lock().await;
+    if let Some((at, pr)) = lookup.as_ref() {
+        if at.elapsed() < LOOKUP_TTL {
+            return Ok(pr.clone());
         }
+    }
+    let pr = lookup_pr(data, github_token, pr_number).await?;
+    *lookup = Some((Instant::now(), pr.clone()));
+    Ok(pr)
+}
+
+// The first-parent commit that made `commit` reachable from each branch it is
+// included in, and the staging cycle that carried it when it landed on master.
+fn find_landings(
+    data: &AppState,
+    branches: &[String],
+    included_in: &[bool],
+    commit: &str,
+) -> Result<(Vec<Option<String>>, Option<StagingCycle>), git2::Error> {
+    let repo = Repository::open(REPO_PATH)?;
+    let commit = Oid::from_str(commit)?;
+    let mut landings = vec![];
+    let mut cycle = None;
+    for (branch, included) in branches.iter().zip(included_in) {
+        if !included {
+            landings.push(None);
+            continue;
+        }
+        let landing = branch_landing_commit(&repo, &data.store, branch, commit)?;
+        if let (Some(landing), "master") = (landing, branch.as_str()) {
+            cycle = staging_cycle(&repo, landing, commit)?;
+        }
+        landings.push(landing.map(|landing| landing.to_string()));
+    }
+    Ok((landings, cycle))
+}
+
+// Builds the status of a PR. `known` caches a PR once it is closed: when set,
+// the lookup is skipped, and it is filled in whenever a closed PR was resolved.
+async fn pr_status(
+    data: &web::Data<AppState>,
+    pr_number: u64,
+    known: &mut Option<PrState>,
+) -> StoreResult<PrStatusObj> {
+    // Never hold the token across an await point.
+    let github_token = data.github_token.lock().unwrap().clone();
+    let state = with_store(data, |store| store.index_state()).await?;
+
+    if state.contains("READY") {
+        let start = Instant::now();
+        let pr = match known {
+            Some(pr) => Ok(pr.clone()),
+            None => shared_lookup(data, &github_token, pr_number).await?,
+        };
+        let (commits, merge_commit) = match pr {
+            Ok(PrState::Open) => {
+                return Ok(PrStatusObj {
+                    success: true,
+                    detail: "".to_string(),
+                    pr: pr_number,
+                    commits: vec![],
+                    merge_commit: "".to_string(),
+                    included_branches: vec![],
+                    included_in: vec![],
+                    landing_commits: vec![],
+                    pipeline: None,
+                    eta: vec![],
+                    staging_cycle: None,
+                    latest_commit: "".to_string(),
+                    network_execution_time: "".to_string(),
+                    redis_execution_time: "".to_string(),
+                })
+            }
+            Ok(PrState::Closed {
+                commits,
+                merged,
+                merge_commit,
+            }) => {
+                *known = Some(PrState::Closed {
+                    commits: commits.clone(),
+                    merged,
+                    merge_commit: merge_commit.clone(),
+                });
+                (commits, merge_commit)
+            }
+            Ok(PrState::Merged {
+                merge_commit,
+                commits,
+            }) => {
+                *known = Some(PrState::Merged {
+                    merge_commit: merge_commit.clone(),
+                    commits: commits.clone(),
+                });
+                (commits, Some(merge_commit))
+            }
+            Err(detail) => {
+                warn!(error = %detail, "Failed to fetch PR from GitHub");
+                return Ok(PrStatusObj {
+                    success: false,
+                    detail,
+                    commits: vec![],
+                    merge_commit: "".to_string(),
+                    pr: pr_number,
+                    included_branches: vec![],
+                    included_in: vec![],
+                    landing_commits: vec![],
+                    pipeline: None,
+                    eta: vec![],
+                    staging_cycle: None,
+                    latest_commit: "".to_string(),
+                    network_execution_time: "".to_string(),
+                    redis_execution_time: "".to_string(),
+                });
+            }
+        };
+        let network_duration = start.elapsed();
+        let start = Instant::now();
+        let config = current_config();
+        let branches = config.branches;
+        // A merge commit is reachable exactly when the PR has landed.
+        let membership = match &merge_commit {
+            Some(merge_commit) => vec![merge_commit.clone()],
+            None => commits.clone(),
+        };
+        let (lookup_data, lookup_branches) = (data.clone(), branches.clone());
+        let included_in =
+            web::block(move || commit_inclusion(&lookup_data.store, &lookup_branches, &membership))
+                .await
+                .map_err(|e| StoreError::Failed(e.to_string()))??;
+        let (topology, lookup_branches, lookup_included, lookup_merge) = (
+            config.topology.clone(),
+            branches.clone(),
+            included_in.clone(),
+            merge_commit.clone(),
+        );
+        let (eta, latest_commit) = with_store(data, move |store| {
+            let eta = match &lookup_merge {
+                Some(merge_commit) => estimate_arrivals(
+                    store,
+                    &topology,
+                    &lookup_branches,
+                    &lookup_included,
+                    merge_commit,
+                ),
+                None => vec![],
+            };
+            let latest_commit = store.branch_tip("master")?.unwrap_or_default();
+            Ok((eta, latest_commit))
+        })
+        .await?;
+        let redis_duration = start.elapsed();
+        let pipeline = pipeline_position(&config.topology, &branches, &included_in);
+        // Once every commit is included, the last one tells how they got there.
+        let landed = merge_commit.as_ref().or(commits.last()).cloned();
+        let unknown = (vec![None; branches.len()], None);
+        let (landing_commits, staging_cycle) = match landed {
+            Some(landed) => {
+                let (data, lookup_branches, lookup_included) =
+                    (data.clone(), branches.clone(), included_in.clone());
+                let landings = web::block(move || {
+                    find_landings(&data, &lookup_branches, &lookup_included, &landed)
+                })
+                .await;
+                match landings {
+                    Ok(Ok(landings)) => landings,
+                    Ok(Err(e)) => {
+                        warn!(error = %e, "Failed to find landing commits");
+                        unknown
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Failed to find landing commits");
+                        unknown
+                    }
+                }
+            }
+            None => unknown,
+        };
+        Ok(PrStatusObj {
+            success: true,
+            detail: "".to_string(),
+            commits,
+            merge_commit: merge_commit.unwrap_or_default(),
+            pr: pr_number,
+            included_branches: branches,
+            included_in,
+            landing_commits,
+            pipeline: Some(pipeline),
+            eta,
+            staging_cycle,
+            latest_commit,
+            network_execution_time: format!("{:?}", network_duration),
+            redis_execution_time: format!("{:?}", redis_duration),
+        })
     } else {
-        web::Json(PrStatusObj {
+        Ok(PrStatusObj {
             success: false,
             detail: "Server is not ready. Try again in few seconds".to_string(),
             pr: pr_number,
             commits: vec![],
+            merge_commit: "".to_string(),
             included_branches: vec![],
             included_in: vec![],
+            landing_commits: vec![],
+            pipeline: None,
+            eta: vec![],
+            staging_cycle: None,
             latest_commit: "".to_string(),
             network_execution_time: "".to_string(),
             redis_execution_time: "".to_string(),
@@ -199,20 +492,66 @@ async fn get_pr_detail(data: web::Data<AppState>, pr: web::Path<u64>) -> impl Re
 }
 
 #[actix_web::main]
-pub async fn server(url: String, port: u16, github_token: String) -> std::io::Result<()> {
+pub async fn server(
+    store_config: StoreConfig,
+    port: u16,
+    github_token: Arc<Mutex<String>>,
+    indexer: Sender<IndexerCommand>,
+    shutdown_timeout: u64,
+    handle_tx: Sender<ServerHandle>,
+) -> std::io::Result<()> {
+    // Fan indexer events out to every open event stream.
+    let (index_events, _) = broadcast::channel(16);
+    let index_events_tx = index_events.clone();
+    let index_events_rx = subscribe_index_events();
+    thread::spawn(move || {
+        for event in index_events_rx {
+            let _ = index_events_tx.send(event);
+        }
+    });
     let app_redis = web::Data::new(AppState {
-        app_redis_connection: Mutex::new(open_redis_connection(url.to_string()).unwrap().unwrap()),
-        github_token: Mutex::new(github_token),
+        store: Mutex::new(
+            store_config
+                .open()
+                .unwrap_or_else(|e| panic!("Failed to open the store: {}", e)),
+        ),
+        github_token,
+        indexer,
+        index_events,
+        pr_lookups: Mutex::new(HashMap::new()),
+    });
+    let jwt_auth = web::Data::new(match JwtAuth::from_env() {
+        Ok(auth) => auth,
+        Err(e) => panic!("Invalid admin API configuration: {}", e),
     });
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let cors = Cors::default().allow_any_origin().send_wildcard();
         App::new()
             .wrap(cors)
+            .wrap(TracingLogger::default())
             .app_data(app_redis.clone())
+            .app_data(jwt_auth.clone())
             .service(index)
+            .service(get_status)
+            .service(get_metrics)
             .service(get_pr_detail)
+            .service(get_pr_events)
+            .service(branch_feed)
+            .service(create_watch)
+            .service(get_watch)
+            .service(remove_watch)
+            .service(admin_scope())
+            .service(
+                web::scope("/webhook")
+                    .app_data(web::PayloadConfig::new(WEBHOOK_PAYLOAD_LIMIT))
+                    .service(github_webhook),
+            )
     })
+    // Signals are handled in main, which stops the server through its handle.
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout)
     .bind(("127.0.0.1", port))?
-    .run()
-    .await
+    .run();
+    let _ = handle_tx.send(server.handle());
+    server.await
 }
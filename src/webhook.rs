@@ -0,0 +1,133 @@
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use tracing::{info, instrument, warn};
+
+use crate::{config::current_config, scheduler::IndexerCommand, web::AppState};
+
+// GitHub payloads for busy branches can be large; the default JSON limit is too small.
+pub const WEBHOOK_PAYLOAD_LIMIT: usize = 5 * 1024 * 1024;
+
+// The subset of a `push` event we care about.
+// https://docs.github.com/en/webhooks/webhook-events-and-payloads#push
+#[derive(Deserialize, Debug)]
+pub struct PushEvent {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub after: String,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+impl PushEvent {
+    // Branch name for pushes to `refs/heads/*`, None for tags and deletions.
+    pub fn branch(&self) -> Option<&str> {
+        match self.deleted {
+            true => None,
+            false => self.git_ref.strip_prefix("refs/heads/"),
+        }
+    }
+}
+
+// Checks the `X-Hub-Signature-256: sha256=<hex>` header against the raw body.
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let signature = match signature
+        .strip_prefix("sha256=")
+        .and_then(|hex_digest| hex::decode(hex_digest).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_e) => return false,
+    };
+    mac.update(body);
+    // verify_slice compares in constant time.
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[post("/github")]
+#[instrument(skip_all, fields(event, delivery))]
+async fn github_webhook(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: web::Bytes,
+) -> impl Responder {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let event = header("X-GitHub-Event");
+    let span = tracing::Span::current();
+    span.record("event", event.as_str());
+    span.record("delivery", header("X-GitHub-Delivery").as_str());
+
+    let secret = current_config().webhook_secret;
+    if secret.is_empty() {
+        return HttpResponse::NotFound().json(json!({
+            "success": false,
+            "detail": "Webhook is disabled",
+        }));
+    }
+    if !verify_signature(&secret, &body, &header("X-Hub-Signature-256")) {
+        warn!("Rejected webhook with invalid signature");
+        return HttpResponse::Unauthorized().json(json!({
+            "success": false,
+            "detail": "Invalid signature",
+        }));
+    }
+
+    match event.as_str() {
+        "ping" => HttpResponse::Ok().json(json!({ "success": true, "detail": "pong" })),
+        "push" => {
+            let push: PushEvent = match serde_json::from_slice(&body) {
+                Ok(push) => push,
+                Err(e) => {
+                    return HttpResponse::BadRequest().json(json!({
+                        "success": false,
+                        "detail": format!("Invalid push payload: {}", e),
+                    }))
+                }
+            };
+            let branch = match push.branch() {
+                Some(branch) if current_config().branches.iter().any(|b| b == branch) => {
+                    branch.to_string()
+                }
+                _ => {
+                    return HttpResponse::Ok().json(json!({
+                        "success": true,
+                        "detail": format!("Ignoring push to {}", push.git_ref),
+                    }))
+                }
+            };
+            info!(
+                branch,
+                after = push.after,
+                "Push to tracked branch. Queueing refresh."
+            );
+            match data
+                .indexer
+                .send(IndexerCommand::IndexBranches(vec![branch.clone()]))
+            {
+                Ok(_) => HttpResponse::Accepted().json(json!({
+                    "success": true,
+                    "detail": format!("Queued refresh of {}", branch),
+                })),
+                Err(_) => HttpResponse::ServiceUnavailable().json(json!({
+                    "success": false,
+                    "detail": "Indexer is not running",
+                })),
+            }
+        }
+        _ => HttpResponse::Ok().json(json!({
+            "success": true,
+            "detail": format!("Ignoring {} event", event),
+        })),
+    }
+}
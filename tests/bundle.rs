@@ -0,0 +1,61 @@
+mod common;
+
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use common::{Fixture, MergedPr, Tracker};
+use httpmock::MockServer;
+use serde_json::json;
+use tempfile::TempDir;
+
+const BRANCHES: [&str; 2] = ["master", "nixos-unstable"];
+
+#[test]
+fn seeded_from_a_bundle_and_updated_from_dropped_bundles() {
+    let (mut fixture, MergedPr { c0, m1, .. }) = Fixture::with_merged_pr();
+    let bundles = TempDir::new().unwrap();
+    let seed = bundles.path().join("nixpkgs.seed");
+    fixture.bundle(&seed, &["master", "nixos-unstable"]);
+    let updates = bundles.path().join("updates");
+    fs::create_dir(&updates).unwrap();
+
+    // No GitHub either: unknown PRs are failures.
+    let github = MockServer::start();
+    let tracker = Tracker::start_with(
+        seed.to_str().unwrap(),
+        &github.base_url(),
+        &BRANCHES,
+        &[("BUNDLE_DIR", updates.to_str().unwrap())],
+    );
+    let status = tracker.get("/pr/1");
+    assert_eq!(status["included_in"], json!([true, false]), "{}", status);
+
+    let f2 = fixture.commit(&[m1], "world: init at 2.0");
+    let m2 = fixture.commit(
+        &[m1, f2],
+        "Merge pull request #2 from bob/world\n\nworld: init at 2.0",
+    );
+    fixture.branch("master", m2);
+    fixture.branch("nixos-unstable", m1);
+    fixture.bundle(
+        &updates.join("0001.bundle"),
+        &[&format!("^{}", c0), "master", "nixos-unstable"],
+    );
+
+    // nixos-unstable is indexed last.
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while tracker.get("/pr/1")["included_in"] != json!([true, true]) {
+        assert!(
+            Instant::now() < deadline,
+            "Bundle was not applied:\n{}",
+            tracker.log()
+        );
+        thread::sleep(Duration::from_millis(200));
+    }
+    let status = tracker.get("/pr/2");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(status["included_in"], json!([true, false]));
+    assert!(updates.join("applied/0001.bundle").is_file());
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use common::{Fixture, Tracker};
+use git2::{ObjectType, Oid, Repository};
+use httpmock::MockServer;
+use serde_json::json;
+
+const BRANCHES: [&str; 2] = ["master", "nixos-unstable"];
+// The message of the PR's commit in `Fixture::with_merged_pr`.
+const FEATURE: &str = "hello: init at 1.0";
+
+fn history() -> (Fixture, Oid) {
+    let (fixture, pr) = Fixture::with_merged_pr();
+    (fixture, pr.f1)
+}
+
+fn assert_pr_indexed(tracker: &Tracker, f1: Oid) {
+    let status = tracker.get("/pr/1");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(status["commits"], json!([f1.to_string()]));
+    assert_eq!(status["included_in"], json!([true, false]));
+}
+
+// The blob the fixture wrote for the PR's commit.
+fn feature_blob() -> Oid {
+    Oid::hash_object(ObjectType::Blob, FEATURE.as_bytes()).unwrap()
+}
+
+fn partial_clone_filter(repo: &Repository) -> Option<String> {
+    repo.config()
+        .unwrap()
+        .get_string("remote.origin.partialclonefilter")
+        .ok()
+}
+
+#[test]
+fn bare_clone_is_indexed() {
+    let (fixture, f1) = history();
+    let github = MockServer::start();
+    let tracker = Tracker::start_with(
+        &fixture.url(),
+        &github.base_url(),
+        &BRANCHES,
+        &[("CLONE_MODE", "bare")],
+    );
+
+    assert_pr_indexed(&tracker, f1);
+    let repo = Repository::open(tracker.repo_path()).unwrap();
+    assert!(repo.is_bare());
+    assert!(repo.find_blob(feature_blob()).is_ok());
+}
+
+#[test]
+fn blobless_clone_leaves_out_blobs() {
+    let (fixture, f1) = history();
+    let github = MockServer::start();
+    let tracker = Tracker::start_with(
+        &fixture.url(),
+        &github.base_url(),
+        &BRANCHES,
+        &[("CLONE_MODE", "blobless")],
+    );
+
+    assert_pr_indexed(&tracker, f1);
+    let repo = Repository::open(tracker.repo_path()).unwrap();
+    assert!(repo.is_bare());
+    assert_eq!(partial_clone_filter(&repo).as_deref(), Some("blob:none"));
+    assert!(repo.find_blob(feature_blob()).is_err());
+}
+
+#[test]
+fn full_clone_is_converted() {
+    let (fixture, f1) = history();
+    let github = MockServer::start();
+    let mut tracker = Tracker::start(&fixture.url(), &github.base_url(), &BRANCHES);
+    assert!(!Repository::open(tracker.repo_path()).unwrap().is_bare());
+
+    tracker.restart_with(&[("CLONE_MODE", "bare")]);
+    assert_pr_indexed(&tracker, f1);
+    assert!(Repository::open(tracker.repo_path()).unwrap().is_bare());
+
+    tracker.restart_with(&[("CLONE_MODE", "treeless")]);
+    assert_pr_indexed(&tracker, f1);
+    let repo = Repository::open(tracker.repo_path()).unwrap();
+    assert_eq!(partial_clone_filter(&repo).as_deref(), Some("tree:0"));
+}
@@ -0,0 +1,319 @@
+// Shared harness for the end-to-end tests: a small nixpkgs-like git repository
+// and the tracker binary running against it.
+
+// Not every test binary uses every helper.
+#![allow(dead_code)]
+
+use std::{
+    fs::{self, File},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use git2::{Oid, Repository, RepositoryInitOptions, Signature, Time};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use reqwest::{blocking::Response, Method};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+// Pass `("JWT_SECRET", ADMIN_SECRET)` to enable the admin API.
+pub const ADMIN_SECRET: &str = "admin-secret";
+
+// A bare repository built commit by commit, served to the tracker over file://.
+pub struct Fixture {
+    dir: TempDir,
+    repo: Repository,
+    clock: i64,
+}
+
+// The commits of `Fixture::with_merged_pr`.
+pub struct MergedPr {
+    pub c0: Oid,
+    pub f1: Oid,
+    pub m1: Oid,
+}
+
+impl Fixture {
+    pub fn new() -> Fixture {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_opts(
+            dir.path(),
+            RepositoryInitOptions::new()
+                .bare(true)
+                .initial_head("master"),
+        )
+        .unwrap();
+        // Lets the tracker make partial clones of it.
+        repo.config()
+            .unwrap()
+            .set_bool("uploadpack.allowFilter", true)
+            .unwrap();
+        Fixture {
+            dir,
+            repo,
+            clock: 1_700_000_000,
+        }
+    }
+
+    // master:         c0 - m1
+    // PR #1:            \  /
+    //                    f1
+    // nixos-unstable: c0
+    pub fn with_merged_pr() -> (Fixture, MergedPr) {
+        let mut fixture = Fixture::new();
+        let c0 = fixture.commit(&[], "Initial commit");
+        let f1 = fixture.commit(&[c0], "hello: init at 1.0");
+        let m1 = fixture.commit(
+            &[c0, f1],
+            "Merge pull request #1 from alice/hello\n\nhello: init at 1.0",
+        );
+        fixture.branch("master", m1);
+        fixture.branch("nixos-unstable", c0);
+        (fixture, MergedPr { c0, f1, m1 })
+    }
+
+    pub fn url(&self) -> String {
+        format!("file://{}", self.dir.path().display())
+    }
+
+    // Each commit adds one file named after it, so merges never conflict.
+    pub fn commit(&mut self, parents: &[Oid], message: &str) -> Oid {
+        self.clock += 60;
+        let signature =
+            Signature::new("Fixture", "fixture@example.com", &Time::new(self.clock, 0)).unwrap();
+        let parents: Vec<_> = parents
+            .iter()
+            .map(|parent| self.repo.find_commit(*parent).unwrap())
+            .collect();
+        let base = parents.first().map(|parent| parent.tree().unwrap());
+        let mut tree = self.repo.treebuilder(base.as_ref()).unwrap();
+        let blob = self.repo.blob(message.as_bytes()).unwrap();
+        tree.insert(format!("{}.txt", self.clock), blob, 0o100644)
+            .unwrap();
+        let tree = self.repo.find_tree(tree.write().unwrap()).unwrap();
+        let parents: Vec<_> = parents.iter().collect();
+        self.repo
+            .commit(None, &signature, &signature, message, &tree, &parents)
+            .unwrap()
+    }
+
+    pub fn branch(&self, name: &str, commit: Oid) {
+        self.repo
+            .reference(&format!("refs/heads/{}", name), commit, true, "fixture")
+            .unwrap();
+    }
+
+    pub fn delete_branch(&self, name: &str) {
+        self.repo
+            .find_reference(&format!("refs/heads/{}", name))
+            .unwrap()
+            .delete()
+            .unwrap();
+    }
+
+    // Writes a git bundle of `revs`, e.g. `["master"]` or `["old..master"]`.
+    pub fn bundle(&self, path: &Path, revs: &[&str]) {
+        let status = Command::new("git")
+            .arg(format!("--git-dir={}", self.dir.path().display()))
+            .args(["bundle", "create", "--quiet"])
+            .arg(path)
+            .args(revs)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    pub fn tag(&self, name: &str, commit: Oid) {
+        self.repo
+            .reference(&format!("refs/tags/{}", name), commit, true, "fixture")
+            .unwrap();
+    }
+}
+
+// The tracker binary, killed when dropped.
+pub struct Tracker {
+    child: Child,
+    dir: TempDir,
+    url: String,
+    repo_url: String,
+    github_url: String,
+    branches: Vec<String>,
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Runs the binary in `dir` on a free port, returning it with its URL.
+fn run(
+    dir: &TempDir,
+    repo_url: &str,
+    github_url: &str,
+    branches: &[String],
+    env: &[(&str, &str)],
+) -> (Child, String) {
+    let port = free_port();
+    let log = File::options()
+        .create(true)
+        .append(true)
+        .open(dir.path().join("tracker.log"))
+        .unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_fast_nixpkgs_tracker"))
+        .current_dir(dir.path())
+        .env_clear()
+        // Partial clones run the git executable.
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .envs(env.iter().copied())
+        .env("REPO_URL", repo_url)
+        .env("GITHUB_API_URL", github_url)
+        .env("TRACKED_BRANCHES", branches.join(","))
+        .env("PORT", port.to_string())
+        .env("REFRESH_INTERVAL", "3600")
+        .env("RUST_LOG", "info")
+        .stdout(log.try_clone().unwrap())
+        .stderr(log)
+        .spawn()
+        .unwrap();
+    (child, format!("http://127.0.0.1:{}", port))
+}
+
+impl Tracker {
+    pub fn start(repo_url: &str, github_url: &str, branches: &[&str]) -> Tracker {
+        Tracker::start_with(repo_url, github_url, branches, &[])
+    }
+
+    pub fn start_with(
+        repo_url: &str,
+        github_url: &str,
+        branches: &[&str],
+        env: &[(&str, &str)],
+    ) -> Tracker {
+        let env: Vec<_> = [("STORE", "memory")].iter().chain(env).copied().collect();
+        let tracker = Tracker::spawn(repo_url, github_url, branches, &env);
+        tracker.wait_ready();
+        tracker
+    }
+
+    // Starts the tracker without waiting for it to index anything.
+    pub fn spawn(
+        repo_url: &str,
+        github_url: &str,
+        branches: &[&str],
+        env: &[(&str, &str)],
+    ) -> Tracker {
+        // The clone lands in `nixpkgs` under the working directory.
+        let dir = TempDir::new().unwrap();
+        let branches: Vec<String> = branches.iter().map(|branch| branch.to_string()).collect();
+        let (child, url) = run(&dir, repo_url, github_url, &branches, env);
+        Tracker {
+            child,
+            dir,
+            url,
+            repo_url: repo_url.to_string(),
+            github_url: github_url.to_string(),
+            branches,
+        }
+    }
+
+    // Restarts the tracker over the same clone with another REPO_URL.
+    pub fn restart_from(&mut self, repo_url: &str) {
+        self.repo_url = repo_url.to_string();
+        self.restart_with(&[]);
+    }
+
+    // Stops the tracker and starts it again over the same clone.
+    pub fn restart_with(&mut self, env: &[(&str, &str)]) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let env: Vec<_> = [("STORE", "memory")].iter().chain(env).copied().collect();
+        let (child, url) = run(
+            &self.dir,
+            &self.repo_url,
+            &self.github_url,
+            &self.branches,
+            &env,
+        );
+        self.child = child;
+        self.url = url;
+        self.wait_ready();
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn repo_path(&self) -> PathBuf {
+        self.dir.path().join("nixpkgs")
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("tracker.log")).unwrap_or_default()
+    }
+
+    // The first response the server gives, whatever its status.
+    pub fn wait_response(&self, path: &str) -> reqwest::blocking::Response {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while Instant::now() < deadline {
+            if let Ok(response) = reqwest::blocking::get(format!("{}{}", self.url, path)) {
+                return response;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("Tracker did not start listening:\n{}", self.log());
+    }
+
+    pub fn wait_ready(&self) {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while Instant::now() < deadline {
+            let state = reqwest::blocking::get(format!("{}/", self.url))
+                .and_then(|response| response.text())
+                .unwrap_or_default();
+            if state == "READY" {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("Tracker did not become ready:\n{}", self.log());
+    }
+
+    pub fn get(&self, path: &str) -> Value {
+        reqwest::blocking::get(format!("{}{}", self.url, path))
+            .unwrap()
+            .json()
+            .unwrap()
+    }
+
+    // Calls an admin endpoint with a token signed with ADMIN_SECRET.
+    pub fn admin(&self, method: Method, path: &str) -> Response {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let token = encode(
+            &Header::default(),
+            &json!({ "sub": "tests", "exp": exp }),
+            &EncodingKey::from_secret(ADMIN_SECRET.as_bytes()),
+        )
+        .unwrap();
+        reqwest::blocking::Client::new()
+            .request(method, format!("{}{}", self.url, path))
+            .bearer_auth(token)
+            .send()
+            .unwrap()
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use common::{Fixture, Tracker};
+use git2::Repository;
+use httpmock::MockServer;
+
+const BRANCHES: [&str; 2] = ["master", "staging"];
+
+// Deletes `staging` and tags `master` upstream after the first start, then
+// starts the tracker again over the same clone.
+fn refetch(clone_mode: &str) {
+    let mut fixture = Fixture::new();
+    let c0 = fixture.commit(&[], "Initial commit");
+    let c1 = fixture.commit(&[c0], "hello: 1.0 -> 1.1 (#1)");
+    fixture.branch("master", c0);
+    fixture.branch("staging", c1);
+    let github = MockServer::start();
+    let env = [("CLONE_MODE", clone_mode)];
+    let mut tracker = Tracker::start_with(&fixture.url(), &github.base_url(), &BRANCHES, &env);
+
+    fixture.branch("master", c1);
+    fixture.delete_branch("staging");
+    fixture.tag("24.05", c1);
+    tracker.restart_with(&env);
+
+    let repo = Repository::open(tracker.repo_path()).unwrap();
+    assert_eq!(
+        repo.refname_to_id("refs/remotes/origin/master").unwrap(),
+        c1
+    );
+    assert!(repo.find_reference("refs/remotes/origin/staging").is_err());
+    assert!(repo.find_reference("refs/tags/24.05").is_err());
+}
+
+#[test]
+fn full_clone_fetches_tracked_branches_only() {
+    refetch("full");
+}
+
+#[test]
+fn partial_clone_fetches_tracked_branches_only() {
+    refetch("blobless");
+}
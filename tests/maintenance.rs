@@ -0,0 +1,54 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{Fixture, Tracker};
+use httpmock::MockServer;
+use serde_json::Value;
+
+#[test]
+fn maintenance_runs_between_passes_and_is_reported() {
+    let mut fixture = Fixture::new();
+    let c0 = fixture.commit(&[], "Initial commit");
+    let c1 = fixture.commit(&[c0], "hello: init at 1.0");
+    fixture.branch("master", c1);
+    let github = MockServer::start();
+    let env = [("MAINTENANCE_INTERVAL", "1")];
+    let mut tracker = Tracker::start_with(&fixture.url(), &github.base_url(), &["master"], &env);
+    // A fresh clone starts the schedule instead of being maintained right away.
+    assert_eq!(tracker.get("/status")["maintenance"]["runs"], 0);
+
+    thread::sleep(Duration::from_millis(1100));
+    tracker.restart_with(&env);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let status: Value = loop {
+        let status = tracker.get("/status");
+        if status["maintenance"]["runs"] == 1 {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "{}\n{}", status, tracker.log());
+        thread::sleep(Duration::from_millis(200));
+    };
+
+    let run = &status["maintenance"]["last"];
+    assert_eq!(run["error"], Value::Null, "{}", status);
+    assert!(run["size_before"].as_u64().unwrap() > 0);
+    assert!(run["size_after"].as_u64().unwrap() > 0);
+    assert!(tracker
+        .repo_path()
+        .join(".git/objects/info/commit-graph")
+        .is_file());
+    let metrics = reqwest::blocking::get(format!("{}/metrics", tracker.url()))
+        .unwrap()
+        .text()
+        .unwrap();
+    assert!(
+        metrics.contains("\ntracker_git_maintenance_runs_total 1\n"),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains("tracker_git_objects_size_bytes{phase=\"after\"}"));
+}
@@ -0,0 +1,322 @@
+mod common;
+
+use common::{Fixture, MergedPr, Tracker, ADMIN_SECRET};
+use std::io::Read;
+
+use git2::Oid;
+use httpmock::{Method::GET, MockServer};
+use reqwest::Method;
+use serde_json::{json, Value};
+
+const BRANCHES: [&str; 3] = ["master", "staging", "nixos-unstable"];
+
+// master:         c0 - m1 - s3 - m5
+// PR #1 and #5:     \  /    \  /
+//                    f1      f5
+// nixos-unstable: m1
+// staging:        m5 - st
+// PR #3 was squashed into s3, PR #4 was closed without landing.
+struct History {
+    fixture: Fixture,
+    f1: Oid,
+    m1: Oid,
+    s3: Oid,
+}
+
+fn history() -> History {
+    let (mut fixture, MergedPr { f1, m1, .. }) = Fixture::with_merged_pr();
+    let s3 = fixture.commit(&[m1], "hello: 1.0 -> 1.1 (#3)");
+    let f5 = fixture.commit(&[s3], "world: init at 2.0");
+    let m5 = fixture.commit(
+        &[s3, f5],
+        "Merge pull request #5 from bob/world\n\nworld: init at 2.0",
+    );
+    let st = fixture.commit(&[m5], "stdenv: rebuild the world");
+    fixture.branch("master", m5);
+    fixture.branch("staging", st);
+    fixture.branch("nixos-unstable", m1);
+    History {
+        fixture,
+        f1,
+        m1,
+        s3,
+    }
+}
+
+fn pull(number: u64, state: &str) -> Value {
+    json!({
+        "url": format!("https://api.github.com/repos/NixOS/nixpkgs/pulls/{}", number),
+        "id": number,
+        "number": number,
+        "state": state,
+        "locked": false,
+        "maintainer_can_modify": false,
+        "head": { "ref": "feature", "sha": "0".repeat(40) },
+        "base": { "ref": "master", "sha": "0".repeat(40) },
+    })
+}
+
+fn mock_pull(github: &MockServer, number: u64, state: &str, commits: &[String]) {
+    github.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/repos/NixOS/nixpkgs/pulls/{}", number));
+        then.status(200).json_body(pull(number, state));
+    });
+    mock_commits(github, number, commits);
+}
+
+// A PR merged by GitHub into `merge_commit`: the merge, squash or last rebased commit.
+fn mock_merged_pull(github: &MockServer, number: u64, merge_commit: Oid, commits: &[String]) {
+    let mut merged = pull(number, "closed");
+    merged["merged_at"] = json!("2023-11-14T22:13:20Z");
+    merged["merge_commit_sha"] = json!(merge_commit.to_string());
+    github.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/repos/NixOS/nixpkgs/pulls/{}", number));
+        then.status(200).json_body(merged);
+    });
+    mock_commits(github, number, commits);
+}
+
+fn mock_commits(github: &MockServer, number: u64, commits: &[String]) {
+    let commits: Vec<Value> = commits.iter().map(|sha| json!({ "sha": sha })).collect();
+    github.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/repos/NixOS/nixpkgs/pulls/{}/commits", number));
+        then.status(200).json_body(json!(commits));
+    });
+}
+
+// `included_in` keyed by branch name.
+fn inclusion(status: &Value) -> Vec<(String, bool)> {
+    let branches = status["included_branches"].as_array().unwrap();
+    let included = status["included_in"].as_array().unwrap();
+    branches
+        .iter()
+        .zip(included)
+        .map(|(branch, included)| {
+            (
+                branch.as_str().unwrap().to_string(),
+                included.as_bool().unwrap(),
+            )
+        })
+        .collect()
+}
+
+fn expected(included: [bool; 3]) -> Vec<(String, bool)> {
+    BRANCHES
+        .iter()
+        .map(|branch| branch.to_string())
+        .zip(included)
+        .collect()
+}
+
+#[test]
+fn merged_pr_is_resolved_from_the_local_merge_index() {
+    let history = history();
+    let github = MockServer::start();
+    let lookup = github.mock(|when, then| {
+        when.path_contains("/pulls/1");
+        then.status(500);
+    });
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    let status = tracker.get("/pr/1");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(status["merge_commit"], history.m1.to_string());
+    assert_eq!(status["commits"], json!([history.f1.to_string()]));
+    assert_eq!(inclusion(&status), expected([true, true, true]));
+    lookup.assert_hits(0);
+}
+
+#[test]
+fn purged_pr_is_looked_up_on_github_again() {
+    let history = history();
+    let github = MockServer::start();
+    mock_pull(&github, 1, "closed", &[history.f1.to_string()]);
+    let tracker = Tracker::start_with(
+        &history.fixture.url(),
+        &github.base_url(),
+        &BRANCHES,
+        &[("JWT_SECRET", ADMIN_SECRET)],
+    );
+
+    let purged: Value = tracker
+        .admin(Method::DELETE, "/admin/cache/pr/1")
+        .json()
+        .unwrap();
+    assert_eq!(purged, json!({ "success": true, "purged": 1 }));
+    let status = tracker.get("/pr/1");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(status["merge_commit"], "");
+    assert_eq!(status["commits"], json!([history.f1.to_string()]));
+    assert_eq!(inclusion(&status), expected([true, true, true]));
+}
+
+#[test]
+fn purging_all_prs_keeps_the_merge_index() {
+    let history = history();
+    let github = MockServer::start();
+    let lookup = github.mock(|when, then| {
+        when.path_contains("/pulls/1");
+        then.status(500);
+    });
+    let tracker = Tracker::start_with(
+        &history.fixture.url(),
+        &github.base_url(),
+        &BRANCHES,
+        &[("JWT_SECRET", ADMIN_SECRET)],
+    );
+    assert_eq!(tracker.get("/pr/1")["merge_commit"], history.m1.to_string());
+
+    let purged: Value = tracker
+        .admin(Method::DELETE, "/admin/cache/pr")
+        .json()
+        .unwrap();
+    assert_eq!(purged, json!({ "success": true, "purged": 1 }));
+    let status = tracker.get("/pr/1");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(status["merge_commit"], history.m1.to_string());
+    lookup.assert_hits(0);
+}
+
+#[test]
+fn merged_pr_missing_from_some_branches() {
+    let history = history();
+    let github = MockServer::start();
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    let status = tracker.get("/pr/5");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(inclusion(&status), expected([true, true, false]));
+}
+
+#[test]
+fn squash_merged_pr_is_looked_up_on_github() {
+    let history = history();
+    let github = MockServer::start();
+    // The PR's own commits were squashed into s3 and never landed.
+    let heads = ["a".repeat(40), "b".repeat(40)];
+    mock_merged_pull(&github, 3, history.s3, &heads);
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    let status = tracker.get("/pr/3");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(status["merge_commit"], history.s3.to_string());
+    assert_eq!(status["commits"], json!(heads));
+    assert_eq!(inclusion(&status), expected([true, true, false]));
+}
+
+#[test]
+fn pr_commits_are_read_from_every_page() {
+    let history = history();
+    let github = MockServer::start();
+    github.mock(|when, then| {
+        when.method(GET).path("/repos/NixOS/nixpkgs/pulls/6");
+        then.status(200).json_body(pull(6, "closed"));
+    });
+    let commits = "/repos/NixOS/nixpkgs/pulls/6/commits";
+    github.mock(|when, then| {
+        when.method(GET).path(commits).query_param("page", "1");
+        then.status(200)
+            .header(
+                "Link",
+                format!(
+                    "<{}{}?per_page=100&page=2>; rel=\"next\", <{}{}?per_page=100&page=2>; rel=\"last\"",
+                    github.base_url(),
+                    commits,
+                    github.base_url(),
+                    commits
+                ),
+            )
+            .json_body(json!([{ "sha": history.f1.to_string() }]));
+    });
+    github.mock(|when, then| {
+        when.method(GET).path(commits).query_param("page", "2");
+        then.status(200)
+            .json_body(json!([{ "sha": history.s3.to_string() }]));
+    });
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    let status = tracker.get("/pr/6");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(
+        status["commits"],
+        json!([history.f1.to_string(), history.s3.to_string()])
+    );
+    assert_eq!(inclusion(&status), expected([true, true, false]));
+}
+
+#[test]
+fn closed_pr_that_never_landed_is_in_no_branch() {
+    let history = history();
+    let github = MockServer::start();
+    mock_pull(&github, 4, "closed", &["4".repeat(40)]);
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    let status = tracker.get("/pr/4");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(inclusion(&status), expected([false, false, false]));
+}
+
+#[test]
+fn open_pr_has_no_inclusion() {
+    let history = history();
+    let github = MockServer::start();
+    mock_pull(&github, 2, "open", &[]);
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    let status = tracker.get("/pr/2");
+    assert_eq!(status["success"], true, "{}", status);
+    assert_eq!(status["included_in"], json!([]));
+}
+
+// Reads the first event of a `GET /pr/{id}/events` stream.
+fn first_event(tracker: &Tracker, pr: u64) -> String {
+    let mut response =
+        reqwest::blocking::get(format!("{}/pr/{}/events", tracker.url(), pr)).unwrap();
+    let mut event = vec![];
+    let mut chunk = [0u8; 1024];
+    while !event.ends_with(b"\n\n") {
+        let read = response.read(&mut chunk).unwrap();
+        assert_ne!(read, 0, "Stream ended early");
+        event.extend_from_slice(&chunk[..read]);
+    }
+    String::from_utf8(event).unwrap()
+}
+
+#[test]
+fn event_streams_of_a_pr_share_github_lookups() {
+    let history = history();
+    let github = MockServer::start();
+    let lookup = github.mock(|when, then| {
+        when.method(GET).path("/repos/NixOS/nixpkgs/pulls/2");
+        then.status(200).json_body(pull(2, "open"));
+    });
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    for _ in 0..3 {
+        let event = first_event(&tracker, 2);
+        assert!(event.starts_with("event: status\n"), "{}", event);
+    }
+    lookup.assert_hits(1);
+}
+
+#[test]
+fn missing_pr_is_reported_as_a_failure() {
+    let history = history();
+    let github = MockServer::start();
+    github.mock(|when, then| {
+        when.method(GET).path("/repos/NixOS/nixpkgs/pulls/404");
+        then.status(404).json_body(json!({
+            "message": "Not Found",
+            "documentation_url": "https://docs.github.com/rest/pulls/pulls#get-a-pull-request",
+        }));
+    });
+    let tracker = Tracker::start(&history.fixture.url(), &github.base_url(), &BRANCHES);
+
+    let status = tracker.get("/pr/404");
+    assert_eq!(status["success"], false, "{}", status);
+    assert_ne!(status["detail"], "");
+    assert_eq!(status["included_in"], json!([]));
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{Fixture, MergedPr, Tracker};
+use git2::Repository;
+use httpmock::MockServer;
+use serde_json::json;
+
+const BRANCHES: [&str; 2] = ["master", "nixos-unstable"];
+
+// Breaks the clone of a running tracker, then starts it again over it and
+// checks that the broken clone was moved aside and cloned again.
+fn recovers_from(damage: impl Fn(&Path)) {
+    let (fixture, _) = Fixture::with_merged_pr();
+    let github = MockServer::start();
+    let mut tracker = Tracker::start(&fixture.url(), &github.base_url(), &BRANCHES);
+
+    damage(&tracker.repo_path());
+    tracker.restart_with(&[]);
+
+    let status = tracker.get("/pr/1");
+    assert_eq!(status["included_in"], json!([true, false]), "{}", status);
+    assert_eq!(moved_aside(&tracker), 1, "{}", tracker.log());
+
+    // Only the latest broken clone is kept.
+    damage(&tracker.repo_path());
+    tracker.restart_with(&[]);
+    assert_eq!(moved_aside(&tracker), 1, "{}", tracker.log());
+}
+
+fn moved_aside(tracker: &Tracker) -> usize {
+    fs::read_dir(tracker.repo_path().parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("nixpkgs.corrupt-"))
+        .count()
+}
+
+#[test]
+fn half_finished_clone_is_cloned_again() {
+    recovers_from(|repo| {
+        fs::remove_dir_all(repo).unwrap();
+        Repository::init(repo).unwrap();
+    });
+}
+
+#[test]
+fn clone_with_lost_objects_is_cloned_again() {
+    recovers_from(|repo| {
+        let objects = repo.join(".git/objects");
+        fs::remove_dir_all(&objects).unwrap();
+        fs::create_dir_all(objects.join("pack")).unwrap();
+    });
+}
+
+#[test]
+fn changed_repo_url_is_fetched_into_the_same_clone() {
+    let (fixture, _) = Fixture::with_merged_pr();
+    let github = MockServer::start();
+    let mut tracker = Tracker::start(&fixture.url(), &github.base_url(), &BRANCHES);
+    let marker = tracker.repo_path().join(".git/tracker-test-marker");
+    fs::write(&marker, "").unwrap();
+
+    // A mirror with one more merge.
+    let (mut mirror, MergedPr { m1, .. }) = Fixture::with_merged_pr();
+    let f2 = mirror.commit(&[m1], "world: init at 2.0");
+    let m2 = mirror.commit(&[m1, f2], "Merge pull request #2 from bob/world");
+    mirror.branch("master", m2);
+    tracker.restart_from(&mirror.url());
+
+    let status = tracker.get("/pr/2");
+    assert_eq!(status["included_in"], json!([true, false]), "{}", status);
+    assert!(marker.exists(), "{}", tracker.log());
+    assert_eq!(moved_aside(&tracker), 0);
+    let repo = Repository::open(tracker.repo_path()).unwrap();
+    let origin = repo.find_remote("origin").unwrap();
+    assert_eq!(origin.url(), Some(mirror.url().as_str()));
+}
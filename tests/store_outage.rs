@@ -0,0 +1,119 @@
+mod common;
+
+use std::{
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{Fixture, Tracker};
+use tempfile::TempDir;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[test]
+fn requests_are_refused_while_redis_is_down() {
+    let mut fixture = Fixture::new();
+    let root = fixture.commit(&[], "Initial commit");
+    fixture.branch("master", root);
+    // Nothing listens on a port that was just released.
+    let port = free_port();
+    let redis_url = format!("redis://127.0.0.1:{}", port);
+    let tracker = Tracker::spawn(
+        &fixture.url(),
+        "http://127.0.0.1:9",
+        &["master"],
+        &[("REDIS_URL", &redis_url)],
+    );
+
+    for path in ["/", "/pr/1"] {
+        let response = tracker.wait_response(path);
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["retry-after"], "5");
+        let body: serde_json::Value = response.json().unwrap();
+        assert_eq!(body["success"], false);
+    }
+}
+
+// A redis-server on `port` that writes every command to an append-only file in
+// `dir`, so that it comes back with its data after being killed. Killed when
+// dropped.
+struct RedisServer(Child);
+
+impl Drop for RedisServer {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn redis_server(dir: &Path, port: u16) -> RedisServer {
+    let port = port.to_string();
+    let child = Command::new("redis-server")
+        .args(["--port", &port, "--bind", "127.0.0.1", "--save", ""])
+        .args(["--appendonly", "yes", "--appendfsync", "always"])
+        .arg("--dir")
+        .arg(dir)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Failed to run redis-server: {}", e));
+    let server = RedisServer(child);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port.parse().unwrap())).is_err() {
+        assert!(Instant::now() < deadline, "redis-server did not start");
+        thread::sleep(Duration::from_millis(50));
+    }
+    server
+}
+
+fn wait_status(tracker: &Tracker, path: &str, status: u16) {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while tracker.wait_response(path).status() != status {
+        assert!(
+            Instant::now() < deadline,
+            "{} never answered {}:\n{}",
+            path,
+            status,
+            tracker.log()
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Run with `cargo test -- --ignored` where redis-server is installed.
+#[test]
+#[ignore = "needs a redis-server executable"]
+fn requests_are_served_again_once_redis_restarts() {
+    let data = TempDir::new().unwrap();
+    let port = free_port();
+    let redis = redis_server(data.path(), port);
+    let mut fixture = Fixture::new();
+    let root = fixture.commit(&[], "Initial commit");
+    fixture.branch("master", root);
+    let redis_url = format!("redis://127.0.0.1:{}", port);
+    let tracker = Tracker::spawn(
+        &fixture.url(),
+        "http://127.0.0.1:9",
+        &["master"],
+        &[("REDIS_URL", &redis_url)],
+    );
+    tracker.wait_ready();
+
+    drop(redis);
+    wait_status(&tracker, "/", 503);
+    let response = tracker.wait_response("/pr/1");
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["retry-after"], "5");
+
+    let _redis = redis_server(data.path(), port);
+    wait_status(&tracker, "/", 200);
+    assert_eq!(tracker.wait_response("/").text().unwrap(), "READY");
+}
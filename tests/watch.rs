@@ -0,0 +1,128 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{Fixture, MergedPr, Tracker, ADMIN_SECRET};
+use httpmock::{
+    Method::{GET, POST},
+    MockServer,
+};
+use reqwest::{blocking::Response, Method, StatusCode};
+use serde_json::{json, Value};
+
+const BRANCHES: [&str; 2] = ["master", "nixos-unstable"];
+const TOKEN: &str = "watch-token";
+
+fn create_watch(tracker: &Tracker, token: Option<&str>, request: &Value) -> Response {
+    let mut builder = reqwest::blocking::Client::new()
+        .post(format!("{}/watch", tracker.url()))
+        .json(request);
+    if let Some(token) = token {
+        builder = builder.bearer_auth(token);
+    }
+    builder.send().unwrap()
+}
+
+#[test]
+fn watch_token_is_required_when_set() {
+    let (fixture, _) = Fixture::with_merged_pr();
+    let github = MockServer::start();
+    let tracker = Tracker::start_with(
+        &fixture.url(),
+        &github.base_url(),
+        &BRANCHES,
+        &[("WATCH_TOKEN", TOKEN)],
+    );
+    let request = json!({
+        "pr": 1,
+        "branches": ["nixos-unstable"],
+        "callback": { "kind": "webhook", "url": "http://127.0.0.1:9/hook" },
+    });
+
+    let response = create_watch(&tracker, None, &request);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = create_watch(&tracker, Some("not-the-token"), &request);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    // The token lets the request through to the callback checks.
+    let response = create_watch(&tracker, Some(TOKEN), &request);
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: Value = response.json().unwrap();
+    assert_eq!(
+        body["detail"], "Callback url must resolve to a public address",
+        "{}",
+        body
+    );
+}
+
+#[test]
+fn watch_on_squash_merged_pr_fires() {
+    // master:         c0 - m1 - s2
+    // nixos-unstable: c0, then s2
+    // PR #2 was squashed into s2, whose message does not name it, so only
+    // GitHub knows where it landed.
+    let (mut fixture, MergedPr { m1, .. }) = Fixture::with_merged_pr();
+    let s2 = fixture.commit(&[m1], "hello: 1.0 -> 1.1");
+    fixture.branch("master", s2);
+    let github = MockServer::start();
+    github.mock(|when, then| {
+        when.method(GET).path("/repos/NixOS/nixpkgs/pulls/2");
+        then.status(200).json_body(json!({
+            "url": "https://api.github.com/repos/NixOS/nixpkgs/pulls/2",
+            "id": 2,
+            "number": 2,
+            "state": "closed",
+            "locked": false,
+            "maintainer_can_modify": false,
+            "merged_at": "2023-11-14T22:13:20Z",
+            "merge_commit_sha": s2.to_string(),
+            "head": { "ref": "feature", "sha": "b".repeat(40) },
+            "base": { "ref": "master", "sha": "0".repeat(40) },
+        }));
+    });
+    github.mock(|when, then| {
+        when.method(GET)
+            .path("/repos/NixOS/nixpkgs/pulls/2/commits");
+        then.status(200)
+            .json_body(json!([{ "sha": "a".repeat(40) }, { "sha": "b".repeat(40) }]));
+    });
+    let subscriber = MockServer::start();
+    let hook = subscriber.mock(|when, then| {
+        when.method(POST)
+            .path("/hook")
+            .json_body_partial(r#"{ "event": "pr_included", "pr": 2 }"#);
+        then.status(200);
+    });
+    let tracker = Tracker::start_with(
+        &fixture.url(),
+        &github.base_url(),
+        &BRANCHES,
+        &[
+            ("WATCH_PRIVATE_CALLBACKS", "true"),
+            ("JWT_SECRET", ADMIN_SECRET),
+        ],
+    );
+
+    let request = json!({
+        "pr": 2,
+        "branches": ["nixos-unstable"],
+        "callback": { "kind": "webhook", "url": subscriber.url("/hook") },
+    });
+    let response = create_watch(&tracker, None, &request);
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body: Value = response.json().unwrap();
+    let id = body["watch"]["id"].as_str().unwrap().to_string();
+
+    fixture.branch("nixos-unstable", s2);
+    let response = tracker.admin(Method::POST, "/admin/branches/nixos-unstable/refresh");
+    assert!(response.status().is_success());
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while hook.hits() == 0 {
+        assert!(Instant::now() < deadline, "{}", tracker.log());
+        thread::sleep(Duration::from_millis(100));
+    }
+    let response = reqwest::blocking::get(format!("{}/watch/{}", tracker.url(), id)).unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,175 @@
+mod common;
+
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use common::{Fixture, Tracker};
+use git2::Oid;
+use hmac::{Hmac, Mac};
+use httpmock::MockServer;
+use reqwest::{blocking::Response, StatusCode};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+const BRANCHES: [&str; 2] = ["master", "nixos-unstable"];
+const SECRET: &str = "webhook-secret";
+
+// `Fixture::with_merged_pr`, with nixos-unstable moved to m1 once the tracker is up.
+struct History {
+    fixture: Fixture,
+    m1: Oid,
+}
+
+fn history() -> History {
+    let (fixture, pr) = Fixture::with_merged_pr();
+    History { fixture, m1: pr.m1 }
+}
+
+fn start(history: &History) -> Tracker {
+    let github = MockServer::start();
+    Tracker::start_with(
+        &history.fixture.url(),
+        &github.base_url(),
+        &BRANCHES,
+        &[("GITHUB_WEBHOOK_SECRET", SECRET)],
+    )
+}
+
+fn payload(name: &str) -> Vec<u8> {
+    fs::read(format!(
+        "{}/tests/fixtures/github/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    ))
+    .unwrap()
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn deliver(tracker: &Tracker, event: &str, body: &[u8], signature: Option<String>) -> Response {
+    let mut request = reqwest::blocking::Client::new()
+        .post(format!("{}/webhook/github", tracker.url()))
+        .header("Content-Type", "application/json")
+        .header("X-GitHub-Event", event)
+        .header("X-GitHub-Delivery", "72d3162e-cc78-11e3-81ab-4c9367dc0958")
+        .body(body.to_vec());
+    if let Some(signature) = signature {
+        request = request.header("X-Hub-Signature-256", signature);
+    }
+    request.send().unwrap()
+}
+
+fn deliver_signed(tracker: &Tracker, event: &str, body: &[u8]) -> (StatusCode, Value) {
+    let response = deliver(tracker, event, body, Some(sign(SECRET, body)));
+    (response.status(), response.json().unwrap())
+}
+
+fn queued_refreshes(tracker: &Tracker) -> usize {
+    tracker.log().matches("Queueing refresh").count()
+}
+
+#[test]
+fn ping_is_answered() {
+    let history = history();
+    let tracker = start(&history);
+
+    let (status, body) = deliver_signed(&tracker, "ping", &payload("ping.json"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "success": true, "detail": "pong" }));
+}
+
+#[test]
+fn missing_signature_is_rejected() {
+    let history = history();
+    let tracker = start(&history);
+
+    let response = deliver(&tracker, "push", &payload("push_nixos_unstable.json"), None);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(queued_refreshes(&tracker), 0);
+}
+
+#[test]
+fn bad_signature_is_rejected() {
+    let history = history();
+    let tracker = start(&history);
+    let body = payload("push_nixos_unstable.json");
+
+    let response = deliver(&tracker, "push", &body, Some(sign("not-the-secret", &body)));
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = deliver(&tracker, "push", &body, Some("sha256=zz".to_string()));
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(queued_refreshes(&tracker), 0);
+}
+
+#[test]
+fn push_refreshes_only_the_pushed_branch() {
+    let mut history = history();
+    let tracker = start(&history);
+    let status = tracker.get("/pr/1");
+    assert_eq!(status["included_in"], json!([true, false]), "{}", status);
+    // Both branches move, but only nixos-unstable is announced.
+    let m2 = history.fixture.commit(&[history.m1], "world: init at 2.0");
+    history.fixture.branch("master", m2);
+    history.fixture.branch("nixos-unstable", history.m1);
+
+    let (status, body) = deliver_signed(&tracker, "push", &payload("push_nixos_unstable.json"));
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+    assert_eq!(body["detail"], "Queued refresh of nixos-unstable");
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let status = tracker.get("/pr/1");
+        if status["included_in"] == json!([true, true]) {
+            assert_eq!(status["latest_commit"], history.m1.to_string());
+            break;
+        }
+        assert!(Instant::now() < deadline, "{}\n{}", status, tracker.log());
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(queued_refreshes(&tracker), 1);
+}
+
+#[test]
+fn tag_push_is_ignored() {
+    let history = history();
+    let tracker = start(&history);
+
+    let (status, body) = deliver_signed(&tracker, "push", &payload("push_tag.json"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["detail"], "Ignoring push to refs/tags/24.05");
+    assert_eq!(queued_refreshes(&tracker), 0);
+}
+
+#[test]
+fn branch_deletion_is_ignored() {
+    let history = history();
+    let tracker = start(&history);
+
+    let (status, body) = deliver_signed(&tracker, "push", &payload("push_branch_deleted.json"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body["detail"],
+        "Ignoring push to refs/heads/backport-123456-to-release-24.05"
+    );
+    assert_eq!(queued_refreshes(&tracker), 0);
+}
+
+#[test]
+fn push_to_untracked_branch_is_ignored() {
+    let history = history();
+    let tracker = start(&history);
+    let mut push: Value = serde_json::from_slice(&payload("push_nixos_unstable.json")).unwrap();
+    push["ref"] = json!("refs/heads/staging");
+    let body = serde_json::to_vec(&push).unwrap();
+
+    let (status, body) = deliver_signed(&tracker, "push", &body);
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["detail"], "Ignoring push to refs/heads/staging");
+    assert_eq!(queued_refreshes(&tracker), 0);
+}